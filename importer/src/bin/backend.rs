@@ -1,52 +1,607 @@
-use clap::Parser;
+use chrono::{NaiveDate, Utc};
+use chrono_tz::Tz;
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use std::error::Error;
+use std::fs::File;
+use std::io;
 use std::path::PathBuf;
 
-use arb_gnucash_importer::blockchain::{self, apply_categories, Categories, Config};
-use arb_gnucash_importer::export::{self, write_csv, write_transfers_csv};
+use arb_gnucash_importer::blockchain::{
+    self, apply_categories, BlockRange, Categories, Config, Transaction,
+};
+use arb_gnucash_importer::decoder::{decode_transactions, AbiRegistry};
+use arb_gnucash_importer::export::{self, write_csv, write_json, write_transfers_csv, Split};
+use arb_gnucash_importer::fees::{self, FeeAccounts};
+use arb_gnucash_importer::interest::{self, Granularity};
+use arb_gnucash_importer::metadata::{apply_metadata, MetadataCache};
+use arb_gnucash_importer::overrides::{apply_overrides, Overrides};
+use arb_gnucash_importer::prices::PriceTable;
+use arb_gnucash_importer::rules::{apply_rules, Rules};
+use arb_gnucash_importer::selectors::{apply_selectors, SelectorDb};
+use arb_gnucash_importer::spam;
+use arb_gnucash_importer::store::{Snapshot, Store};
+use arb_gnucash_importer::tags::{apply_tags, Tags};
+use arb_gnucash_importer::{bridge, events, reconcile, report};
+use ethers::providers::Middleware;
 use ethers::types::Address;
 
 /// Command line arguments for the backend tool
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
-struct Args {
-    /// Arbitrum address
+struct Cli {
+    /// Config file (TOML, YAML or JSON), defaults to `config.yml` or `config.toml`
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Named profile of the config file to use
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    /// Directory storing the fetched and categorized transactions [default: store]
+    #[arg(long, global = true)]
+    store: Option<PathBuf>,
+
+    /// IANA time zone transactions are dated in, e.g. Europe/Berlin [default: UTC]
+    #[arg(long, global = true)]
+    timezone: Option<Tz>,
+
+    /// Optional directory of `<address>.json` contract ABIs used to decode
+    /// calldata and events
+    #[arg(long, global = true)]
+    abi_dir: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Fetch the transactions of an address into the store
+    Fetch(FetchArgs),
+    /// Decode and categorize the stored transactions
+    Categorize(CategorizeArgs),
+    /// Export the categorized transactions
+    Export(ExportArgs),
+    /// Write tax, balance, fee and NFT reports from the stored transactions
+    Report(ReportArgs),
+    /// Compare the exported balances with the current on-chain balances
+    Reconcile(ReconcileArgs),
+    /// Fetch new transactions, then categorize and export everything stored
+    Sync(Box<SyncArgs>),
+}
+
+#[derive(Args, Debug)]
+struct FetchArgs {
+    /// Arbitrum address, defaults to the `address` of the config
+    #[arg(long)]
+    address: Option<String>,
+
+    /// Only fetch transactions on or after this date (YYYY-MM-DD)
+    #[arg(long)]
+    from: Option<NaiveDate>,
+
+    /// Only fetch transactions on or before this date (YYYY-MM-DD)
+    #[arg(long)]
+    to: Option<NaiveDate>,
+
+    /// Only fetch transactions mined in or after this block
     #[arg(long)]
-    address: String,
+    start_block: Option<u64>,
 
-    /// Output file path
+    /// Only fetch transactions mined in or before this block
     #[arg(long)]
-    output: PathBuf,
+    end_block: Option<u64>,
 
+    /// Read token decimals, symbols and names from the token contracts instead
+    /// of trusting the explorer
+    #[arg(long)]
+    resolve_tokens: bool,
+
+    /// File caching token metadata read from the chain
+    #[arg(long, default_value = "token_metadata.json")]
+    token_cache: PathBuf,
+
+    /// Fetch transaction receipts and decode their event logs
+    #[arg(long)]
+    events: bool,
+
+    /// Fetch transaction receipts and read the L2 execution and L1 data fees
+    /// paid by the address
+    #[arg(long)]
+    fees: bool,
+
+    /// Optional file path to write the transfers filtered out as spam
+    #[arg(long)]
+    spam_report: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct CategorizeArgs {
     /// Optional config file mapping addresses to transaction categories
     #[arg(long)]
     categories: Option<PathBuf>,
 
-    /// Optional file path to write token transfer details
+    /// Optional file of extra function signatures used to guess unknown methods
+    #[arg(long)]
+    selectors: Option<PathBuf>,
+
+    /// Optional file mapping addresses to counterparty names
+    #[arg(long)]
+    tags: Option<PathBuf>,
+
+    /// Optional file of ordered categorization rules, applied after categories
+    #[arg(long)]
+    rules: Option<PathBuf>,
+
+    /// Optional file of per-transaction overrides keyed by transaction hash
     #[arg(long)]
-    transfers_output: Option<PathBuf>,
+    overrides: Option<PathBuf>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    // initialize logging from log4rs config file
-    log4rs::init_file("log4rs.yml", Default::default()).expect("failed to init logger");
+/// Options turning stored transactions into splits
+#[derive(Args, Debug)]
+struct BookArgs {
+    /// Expense account for the L2 execution part of fees
+    #[arg(long, default_value = "Expenses:Fees:L2 Execution")]
+    l2_fee_account: String,
+
+    /// Expense account for the L1 data part of fees
+    #[arg(long, default_value = "Expenses:Fees:L1 Data")]
+    l1_fee_account: String,
+
+    /// Book the commodities of every merge group, such as USDC.e and USDC, as one
+    #[arg(long)]
+    merge_commodities: bool,
+
+    /// Book interest accrued on Aave aTokens and debt tokens per period
+    /// (daily, weekly or monthly)
+    #[arg(long)]
+    interest: Option<Granularity>,
+}
+
+/// File formats written by `export`
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Format {
+    /// GnuCash CSV import
+    Gnucash,
+    /// Splits as a JSON array
+    Json,
+    /// Token transfer details as CSV
+    Transfers,
+}
 
-    let args = Args::parse();
-    let cfg = Config::load(None)?;
-    let _provider = blockchain::provider(&cfg).await?;
-    let client = blockchain::etherscan_client(&cfg)?;
+#[derive(Args, Debug)]
+struct ExportArgs {
+    /// Output file path, defaults to the `output` of the config
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = Format::Gnucash)]
+    format: Format,
+
+    /// Write the full local time of each transaction instead of only its date
+    #[arg(long)]
+    timestamps: bool,
+
+    /// Ethereum address whose bridge transactions are linked with this export,
+    /// defaults to the `l1_address` of the config
+    #[arg(long, requires = "l1_output")]
+    l1_address: Option<String>,
+
+    /// File path to write the Ethereum side of bridge transfers
+    #[arg(long)]
+    l1_output: Option<PathBuf>,
+
+    #[command(flatten)]
+    book: BookArgs,
+}
+
+#[derive(Args, Debug)]
+struct ReportArgs {
+    /// Optional CSV file of historical fiat prices (date, commodity, price)
+    #[arg(long)]
+    prices: Option<PathBuf>,
+
+    /// Optional file path to write a Form 8949-style tax report
+    #[arg(long, requires = "tax_year")]
+    tax_report: Option<PathBuf>,
+
+    /// Tax year covered by the tax report
+    #[arg(long)]
+    tax_year: Option<i32>,
+
+    /// Print per-commodity balances as of the end of this date (YYYY-MM-DD)
+    #[arg(long)]
+    balance_date: Option<NaiveDate>,
+
+    /// Cross-check the printed balances against the chain
+    #[arg(long, requires = "balance_date")]
+    check_balances: bool,
+
+    /// Optional file path to write the NFTs currently held
+    #[arg(long)]
+    nft_holdings: Option<PathBuf>,
 
-    let address: Address = args.address.parse()?;
-    let mut txs = blockchain::fetch_transactions(&client, address).await?;
+    /// Optional file path to write a monthly fee summary
+    #[arg(long)]
+    fee_report: Option<PathBuf>,
+
+    #[command(flatten)]
+    book: BookArgs,
+}
+
+#[derive(Args, Debug)]
+struct ReconcileArgs {
+    /// Largest difference between exported and on-chain balances to ignore
+    #[arg(long, default_value_t = 1e-6)]
+    tolerance: f64,
+
+    #[command(flatten)]
+    book: BookArgs,
+}
+
+#[derive(Args, Debug)]
+struct SyncArgs {
+    #[command(flatten)]
+    fetch: FetchArgs,
+
+    #[command(flatten)]
+    categorize: CategorizeArgs,
+
+    #[command(flatten)]
+    export: ExportArgs,
+}
+
+/// Options shared by every command. Command line flags take precedence over the
+/// config, which already applies environment variables and the profile.
+struct Settings {
+    cfg: Config,
+    store: Store,
+    timezone: Tz,
+    abi_dir: Option<PathBuf>,
+}
+
+impl Settings {
+    fn resolve(cli: &Cli) -> Result<Self, Box<dyn Error>> {
+        let cfg = Config::load(cli.config.as_deref(), cli.profile.as_deref())?;
+        let timezone = match (cli.timezone, cfg.timezone.as_deref()) {
+            (Some(tz), _) => tz,
+            (None, Some(name)) => name.parse()?,
+            (None, None) => Tz::UTC,
+        };
+        let store = cli
+            .store
+            .clone()
+            .or_else(|| cfg.store.clone())
+            .unwrap_or_else(|| PathBuf::from("store"));
+        Ok(Self {
+            store: Store::new(&store),
+            timezone,
+            abi_dir: cli.abi_dir.clone(),
+            cfg,
+        })
+    }
+
+    /// Return `address`, falling back to the wallet of the config.
+    fn address(&self, address: Option<&str>) -> Result<Address, Box<dyn Error>> {
+        let address = address
+            .or(self.cfg.address.as_deref())
+            .ok_or("no address given, pass --address or set address in the config")?;
+        Ok(address.parse()?)
+    }
+
+    fn abi_registry(&self) -> Result<AbiRegistry, Box<dyn Error>> {
+        match self.abi_dir.as_deref() {
+            Some(dir) => AbiRegistry::load_dir(dir),
+            None => Ok(AbiRegistry::default()),
+        }
+    }
+}
+
+/// Fetch the transactions selected by `args`, starting no earlier than `since`.
+/// Recipients paid in the `stored` transactions are known to the spam filter.
+async fn fetch(
+    settings: &Settings,
+    args: &FetchArgs,
+    stored: &Snapshot,
+    since: Option<u64>,
+) -> Result<(BlockRange, Vec<Transaction>), Box<dyn Error>> {
+    let provider = blockchain::provider(&settings.cfg).await?;
+    let client = blockchain::etherscan_client(&settings.cfg)?;
+    let address = settings.address(args.address.as_deref())?;
+    let mut range = blockchain::block_range(
+        &client,
+        args.from,
+        args.to,
+        args.start_block,
+        args.end_block,
+        settings.timezone,
+    )
+    .await?;
+    range.start = range.start.max(since.unwrap_or_default());
+    let mut txs = blockchain::fetch_transactions_range(&client, address, range).await?;
+    let spam = spam::filter_spam(address, &mut txs, &stored.transactions);
+    if let Some(path) = args.spam_report.as_deref() {
+        spam::write_spam_report(path, &spam)?;
+    }
+    if args.resolve_tokens {
+        let mut cache = MetadataCache::load(&args.token_cache)?;
+        for m in apply_metadata(&provider, &mut txs, &mut cache).await? {
+            log::warn!(
+                "token {:#x} {} mismatch: explorer reports {:?}, contract {:?}",
+                m.token,
+                m.field,
+                m.explorer,
+                m.on_chain
+            );
+        }
+        cache.save(&args.token_cache)?;
+    }
+    // Events need every receipt and fees only those of sent transactions;
+    // each is fetched once and shared by both.
+    let needed = txs
+        .iter()
+        .filter(|tx| args.events || (args.fees && tx.from == address && !tx.transfer_only))
+        .map(|tx| tx.hash);
+    let receipts = blockchain::fetch_receipts(&provider, needed).await?;
+    if args.events {
+        events::fetch_events(&receipts, &mut txs, &settings.abi_registry()?).await?;
+    }
+    if args.fees {
+        fees::fetch_fees(&receipts, address, &mut txs).await?;
+    }
+    Ok((range, txs))
+}
+
+/// Decode and categorize the transactions of `snapshot` in place.
+fn categorize(
+    settings: &Settings,
+    args: &CategorizeArgs,
+    snapshot: &mut Snapshot,
+) -> Result<(), Box<dyn Error>> {
+    let address = snapshot.address;
+    let txs = &mut snapshot.transactions;
+    decode_transactions(txs, &settings.abi_registry()?);
+    let mut selectors = SelectorDb::bundled();
+    if let Some(path) = args.selectors.as_deref() {
+        selectors.extend_from_file(path)?;
+    }
+    for hash in apply_selectors(txs, &selectors) {
+        log::warn!("ambiguous function selector in transaction {:#x}", hash);
+    }
+    if let Some(path) = args.tags.as_deref() {
+        let tags = Tags::load(path)?;
+        apply_tags(address, txs, &tags);
+    }
     if let Some(cat_path) = args.categories.as_deref() {
         let cats = Categories::load(cat_path)?;
-        apply_categories(&mut txs, &cats);
+        apply_categories(txs, &cats);
+    }
+    if let Some(rules_path) = args.rules.as_deref() {
+        let rules = Rules::load(rules_path)?;
+        apply_rules(address, txs, &rules);
     }
-    let gnucash_txs = export::from_chain(address, &txs);
-    write_csv(&args.output, &gnucash_txs)?;
-    if let Some(path) = args.transfers_output.as_deref() {
-        write_transfers_csv(path, &txs)?;
+    if let Some(path) = args.overrides.as_deref() {
+        let overrides = Overrides::load(path)?;
+        apply_overrides(txs, &overrides);
     }
     Ok(())
 }
+
+/// Turn the stored transactions into splits, including fees read by `fetch`
+/// and, if requested, accrued interest.
+async fn book(
+    settings: &Settings,
+    args: &BookArgs,
+    snapshot: &Snapshot,
+) -> Result<Vec<Split>, Box<dyn Error>> {
+    let txs = &snapshot.transactions;
+    let mut splits = export::from_chain_in(snapshot.address, txs, settings.timezone);
+    let accounts = FeeAccounts {
+        l2: args.l2_fee_account.clone(),
+        l1: args.l1_fee_account.clone(),
+    };
+    splits.extend(fees::fee_splits(txs, &accounts, settings.timezone));
+    if let Some(granularity) = args.interest {
+        require_full_history(snapshot, "interest accrual")?;
+        let provider = blockchain::provider(&settings.cfg).await?;
+        let client = blockchain::etherscan_client(&settings.cfg)?;
+        // accrue up to the end of the fetched range, which may lie in the past
+        let today = Utc::now().with_timezone(&settings.timezone).date_naive();
+        let end = match snapshot.range {
+            Some(range) if !range.is_open() => provider.get_block(range.end).await?,
+            _ => None,
+        };
+        let until = end
+            .map(|block| export::local_time(block.timestamp.as_u64(), settings.timezone))
+            .map_or(today, |time| time.date_naive().min(today));
+        let accrued = interest::accrue_in(
+            &client,
+            &provider,
+            snapshot.address,
+            txs,
+            granularity,
+            until,
+            settings.timezone,
+        )
+        .await?;
+        splits.extend(accrued);
+    }
+    if args.merge_commodities {
+        export::merge_commodities(&mut splits, &settings.cfg.merge_groups);
+    }
+    Ok(splits)
+}
+
+async fn export(
+    settings: &Settings,
+    args: &ExportArgs,
+    snapshot: &Snapshot,
+) -> Result<(), Box<dyn Error>> {
+    let output = args
+        .output
+        .clone()
+        .or_else(|| settings.cfg.output.clone())
+        .ok_or("no output file given, pass --output or set output in the config")?;
+    let mut splits = match args.format {
+        Format::Transfers => return write_transfers_csv(&output, &snapshot.transactions),
+        Format::Gnucash | Format::Json => book(settings, &args.book, snapshot).await?,
+    };
+    if let Some(path) = args.l1_output.as_deref() {
+        let l1_address: Address = args
+            .l1_address
+            .as_deref()
+            .or(settings.cfg.l1_address.as_deref())
+            .ok_or("no L1 address given, pass --l1-address or set l1_address in the config")?
+            .parse()?;
+        let l1_client = blockchain::l1_etherscan_client(&settings.cfg)?;
+        let l1_txs = blockchain::fetch_transactions(&l1_client, l1_address).await?;
+        let l1_internal = blockchain::fetch_internal_transfers(&l1_client, l1_address).await?;
+        let mut l1_splits = bridge::l1_splits(l1_address, &l1_txs, &l1_internal, settings.timezone);
+        bridge::link(&mut splits, &mut l1_splits);
+        match args.format {
+            Format::Json => write_json(path, &l1_splits)?,
+            _ => write_csv(path, &l1_splits, args.timestamps)?,
+        }
+    }
+    match args.format {
+        Format::Json => write_json(&output, &splits),
+        _ => write_csv(&output, &splits, args.timestamps),
+    }
+}
+
+async fn report(
+    settings: &Settings,
+    args: &ReportArgs,
+    snapshot: &Snapshot,
+) -> Result<(), Box<dyn Error>> {
+    let splits = book(settings, &args.book, snapshot).await?;
+    let prices = match args.prices.as_deref() {
+        Some(p) => PriceTable::load(p)?,
+        None => PriceTable::default(),
+    };
+    if let (Some(path), Some(year)) = (args.tax_report.as_deref(), args.tax_year) {
+        require_full_history(snapshot, "the tax report")?;
+        let disposals = report::tax_report(&splits, &prices, year);
+        report::write_tax_csv(path, &disposals)?;
+    }
+    if let Some(date) = args.balance_date {
+        require_full_history(snapshot, "the balance report")?;
+        let mut balances = report::balance_report(&splits, &prices, date);
+        if args.check_balances {
+            let provider = blockchain::provider(&settings.cfg).await?;
+            let client = blockchain::etherscan_client(&settings.cfg)?;
+            let block = blockchain::block_at_end_of_in(&client, date, settings.timezone).await?;
+            report::cross_check(
+                &mut balances,
+                &provider,
+                snapshot.address,
+                Some(block),
+                args.book
+                    .merge_commodities
+                    .then_some(&settings.cfg.merge_groups),
+            )
+            .await?;
+        }
+        report::write_balances(io::stdout(), &balances)?;
+    }
+    if let Some(path) = args.nft_holdings.as_deref() {
+        let holdings = report::nft_holdings(snapshot.address, &snapshot.transactions);
+        report::write_nft_holdings(File::create(path)?, &holdings)?;
+    }
+    if let Some(path) = args.fee_report.as_deref() {
+        let summary = fees::monthly_summary(&snapshot.transactions, settings.timezone);
+        fees::write_fee_summary(File::create(path)?, &summary)?;
+    }
+    Ok(())
+}
+
+async fn reconcile(
+    settings: &Settings,
+    args: &ReconcileArgs,
+    snapshot: &Snapshot,
+) -> Result<(), Box<dyn Error>> {
+    require_full_history(snapshot, "reconcile")?;
+    let splits = book(settings, &args.book, snapshot).await?;
+    let provider = blockchain::provider(&settings.cfg).await?;
+    // compare with the chain where the fetched range ends
+    let block = snapshot.range.filter(|r| !r.is_open()).map(|r| r.end);
+    let discrepancies = reconcile::reconcile(
+        snapshot.address,
+        &snapshot.transactions,
+        &splits,
+        &provider,
+        block,
+        args.tolerance,
+        args.book
+            .merge_commodities
+            .then_some(&settings.cfg.merge_groups),
+    )
+    .await?;
+    reconcile::write_discrepancies(io::stdout(), &discrepancies)
+}
+
+/// Fail unless `snapshot` covers the whole history of its address, since `what`
+/// replays every transaction starting from an empty wallet.
+fn require_full_history(snapshot: &Snapshot, what: &str) -> Result<(), Box<dyn Error>> {
+    if snapshot.has_full_history() {
+        return Ok(());
+    }
+    Err(format!(
+        "{} needs the full history, but the store holds transactions from block {} on; \
+         fetch again without --from and --start-block",
+        what,
+        snapshot.range.map(|r| r.start).unwrap_or_default()
+    )
+    .into())
+}
+
+/// Fetched transactions of `address` kept in the store, or an empty snapshot if
+/// none are stored or they belong to another address.
+fn stored(store: &Store, address: Address) -> Result<Snapshot, Box<dyn Error>> {
+    Ok(match store.fetched()? {
+        Some(stored) if stored.address == address => stored,
+        _ => Snapshot::new(address),
+    })
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // initialize logging from log4rs config file
+    log4rs::init_file("log4rs.yml", Default::default()).expect("failed to init logger");
+
+    let cli = Cli::parse();
+    let settings = Settings::resolve(&cli)?;
+    let store = &settings.store;
+
+    match &cli.command {
+        Command::Fetch(args) => {
+            let mut snapshot = stored(store, settings.address(args.address.as_deref())?)?;
+            let (range, txs) = fetch(&settings, args, &snapshot, None).await?;
+            snapshot.merge(range, txs);
+            store.save_fetched(&snapshot)
+        }
+        Command::Categorize(args) => {
+            let mut snapshot = store
+                .fetched()?
+                .ok_or("no transactions stored, run `fetch` first")?;
+            categorize(&settings, args, &mut snapshot)?;
+            store.save_categorized(&snapshot)
+        }
+        Command::Export(args) => export(&settings, args, &store.categorized()?).await,
+        Command::Report(args) => report(&settings, args, &store.categorized()?).await,
+        Command::Reconcile(args) => reconcile(&settings, args, &store.categorized()?).await,
+        Command::Sync(args) => {
+            let address = settings.address(args.fetch.address.as_deref())?;
+            let mut snapshot = stored(store, address)?;
+            let since = snapshot.latest_block();
+            let (range, txs) = fetch(&settings, &args.fetch, &snapshot, since).await?;
+            snapshot.merge(range, txs);
+            store.save_fetched(&snapshot)?;
+            categorize(&settings, &args.categorize, &mut snapshot)?;
+            store.save_categorized(&snapshot)?;
+            export(&settings, &args.export, &snapshot).await
+        }
+    }
+}
@@ -3,51 +3,155 @@ use std::error::Error;
 use std::fs;
 
 use async_trait::async_trait;
+use chrono::{LocalResult, NaiveDate, TimeZone};
+use chrono_tz::Tz;
 use ethers::{
+    abi::{self, Token},
     etherscan::{
-        account::{ERC20TokenTransferEvent, NormalTransaction, TokenQueryOption, TxListParams},
+        account::{
+            ERC1155TokenTransferEvent, ERC20TokenTransferEvent, ERC721TokenTransferEvent,
+            InternalTransaction, InternalTxQueryOption, NormalTransaction, TokenQueryOption,
+            TxListParams,
+        },
         Client as EtherscanClient,
     },
-    providers::{Http, Provider},
-    types::{Address, Chain, H256, U256},
+    providers::{Http, Middleware, Provider},
+    types::{
+        Address, BlockId, BlockNumber, Bytes, Chain, TransactionReceipt, TransactionRequest, H256,
+        U256,
+    },
 };
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize};
 use std::collections::HashMap;
-use std::path::Path;
+use std::future::Future;
+use std::path::{Path, PathBuf};
 
-/// Configuration for connecting to the Arbitrum network.
-#[derive(Debug, Deserialize)]
+use crate::decoder::DecodedCall;
+use crate::events::DecodedEvent;
+use crate::fees::Fee;
+use crate::tokens::MergeGroups;
+
+/// Settings of a named profile in the config file. Fields left out fall back to
+/// the top level of the file.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Profile {
+    pub rpc_url: Option<String>,
+    /// Network queried through the explorer, e.g. `arbitrum` or `arbitrum_nova`
+    pub chain: Option<Chain>,
+    pub etherscan_api_key: Option<String>,
+    /// Etherscan API key for Ethereum mainnet, used for the L1 side of bridges
+    pub l1_etherscan_api_key: Option<String>,
+    /// Wallet address to import
+    pub address: Option<String>,
+    /// Ethereum wallet whose bridge transactions are linked with the import
+    pub l1_address: Option<String>,
+    /// Directory storing fetched and categorized transactions
+    pub store: Option<PathBuf>,
+    /// IANA time zone transactions are dated in
+    pub timezone: Option<String>,
+    /// Default export file
+    pub output: Option<PathBuf>,
+    /// Commodities booked as one by `--merge-commodities`
+    pub merge_groups: Option<MergeGroups>,
+}
+
+impl Profile {
+    /// Fill the fields missing from `self` with those of `fallback`.
+    fn or(self, fallback: Profile) -> Profile {
+        Profile {
+            rpc_url: self.rpc_url.or(fallback.rpc_url),
+            chain: self.chain.or(fallback.chain),
+            etherscan_api_key: self.etherscan_api_key.or(fallback.etherscan_api_key),
+            l1_etherscan_api_key: self.l1_etherscan_api_key.or(fallback.l1_etherscan_api_key),
+            address: self.address.or(fallback.address),
+            l1_address: self.l1_address.or(fallback.l1_address),
+            store: self.store.or(fallback.store),
+            timezone: self.timezone.or(fallback.timezone),
+            output: self.output.or(fallback.output),
+            merge_groups: self.merge_groups.or(fallback.merge_groups),
+        }
+    }
+}
+
+/// Contents of a config file: top-level defaults and named profiles
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    #[serde(flatten)]
+    defaults: Profile,
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+}
+
+/// Configuration for connecting to the Arbitrum network, along with defaults
+/// for the wallet and outputs.
+#[derive(Debug)]
 pub struct Config {
     pub rpc_url: String,
-    #[serde(default)]
+    pub chain: Chain,
     pub etherscan_api_key: Option<String>,
+    /// Etherscan API key for Ethereum mainnet, used for the L1 side of bridges
+    pub l1_etherscan_api_key: Option<String>,
+    pub address: Option<String>,
+    pub l1_address: Option<String>,
+    pub store: Option<PathBuf>,
+    pub timezone: Option<String>,
+    pub output: Option<PathBuf>,
+    /// Commodities booked as one by `--merge-commodities`, the groups of
+    /// [`MergeGroups::builtin`] unless configured
+    pub merge_groups: MergeGroups,
 }
 
 impl Config {
-    /// Load configuration from the `ARBITRUM_RPC_URL` environment variable or
-    /// from the provided config file. The file format is inferred from the
-    /// extension and may be TOML, YAML or JSON. If `path` is `None`,
-    /// `config.toml` will be attempted.
-    pub fn load(path: Option<&str>) -> Result<Self, Box<dyn Error>> {
-        if let Ok(url) = env::var("ARBITRUM_RPC_URL") {
-            return Ok(Self {
-                rpc_url: url,
-                etherscan_api_key: env::var("ETHERSCAN_API_KEY").ok(),
-            });
+    /// Load configuration from the provided config file. The file format is
+    /// inferred from the extension and may be TOML, YAML or JSON. If `path` is
+    /// `None`, `config.yml` and then `config.toml` are attempted, and a missing
+    /// file is not an error.
+    ///
+    /// Values of `profile` take precedence over the `ARBITRUM_RPC_URL`,
+    /// `ETHERSCAN_API_KEY` and `L1_ETHERSCAN_API_KEY` environment variables,
+    /// which in turn take precedence over the top level of the file.
+    pub fn load(path: Option<&Path>, profile: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let default = ["config.yml", "config.toml"]
+            .into_iter()
+            .map(Path::new)
+            .find(|p| p.exists());
+        let file: ConfigFile = match path.or(default) {
+            Some(path) => load_structured(path)?,
+            None => ConfigFile::default(),
+        };
+        let mut defaults = file.defaults;
+        for (var, field) in [
+            ("ARBITRUM_RPC_URL", &mut defaults.rpc_url),
+            ("ETHERSCAN_API_KEY", &mut defaults.etherscan_api_key),
+            ("L1_ETHERSCAN_API_KEY", &mut defaults.l1_etherscan_api_key),
+        ] {
+            if let Ok(value) = env::var(var) {
+                *field = Some(value);
+            }
         }
-
-        let path = path.unwrap_or("config.yml");
-        let contents = fs::read_to_string(path)?;
-        let ext = Path::new(path)
-            .extension()
-            .and_then(|e| e.to_str())
-            .unwrap_or("yml");
-        let cfg = match ext {
-            "json" => serde_json::from_str(&contents)?,
-            "yaml" | "yml" => serde_yaml::from_str(&contents)?,
-            _ => toml::from_str(&contents)?,
+        let settings = match profile {
+            Some(name) => file
+                .profiles
+                .get(name)
+                .cloned()
+                .ok_or_else(|| format!("unknown profile {:?}", name))?
+                .or(defaults),
+            None => defaults,
         };
-        Ok(cfg)
+        Ok(Self {
+            rpc_url: settings
+                .rpc_url
+                .ok_or("no rpc_url configured, set it in the config file or ARBITRUM_RPC_URL")?,
+            chain: settings.chain.unwrap_or(Chain::Arbitrum),
+            etherscan_api_key: settings.etherscan_api_key,
+            l1_etherscan_api_key: settings.l1_etherscan_api_key,
+            address: settings.address,
+            l1_address: settings.l1_address,
+            store: settings.store,
+            timezone: settings.timezone,
+            output: settings.output,
+            merge_groups: settings.merge_groups.unwrap_or_else(MergeGroups::builtin),
+        })
     }
 }
 
@@ -57,17 +161,26 @@ pub async fn provider(cfg: &Config) -> Result<Provider<Http>, Box<dyn Error>> {
     Ok(provider)
 }
 
-/// Create an [`EtherscanClient`] for the Arbitrum network using an optional API key.
+/// Create an [`EtherscanClient`] for the configured network using an optional API key.
 pub fn etherscan_client(cfg: &Config) -> Result<EtherscanClient, Box<dyn Error>> {
     if let Some(ref key) = cfg.etherscan_api_key {
-        Ok(EtherscanClient::new(Chain::Arbitrum, key)?)
+        Ok(EtherscanClient::new(cfg.chain, key)?)
+    } else {
+        Ok(EtherscanClient::new_from_opt_env(cfg.chain)?)
+    }
+}
+
+/// Create an [`EtherscanClient`] for Ethereum mainnet using the optional L1 API key.
+pub fn l1_etherscan_client(cfg: &Config) -> Result<EtherscanClient, Box<dyn Error>> {
+    if let Some(ref key) = cfg.l1_etherscan_api_key {
+        Ok(EtherscanClient::new(Chain::Mainnet, key)?)
     } else {
-        Ok(EtherscanClient::new_from_opt_env(Chain::Arbitrum)?)
+        Ok(EtherscanClient::new_from_opt_env(Chain::Mainnet)?)
     }
 }
 
 /// Simplified transaction information returned by [`fetch_transactions`].
-#[derive(Clone, Debug, serde::Serialize)]
+#[derive(Clone, Debug, Default, serde::Serialize, Deserialize)]
 pub struct Transaction {
     pub hash: H256,
     pub block_number: u64,
@@ -75,16 +188,48 @@ pub struct Transaction {
     pub from: Address,
     pub to: Option<Address>,
     pub value: U256,
+    /// Function selector reported by the explorer
+    pub method_id: Option<Bytes>,
+    /// Function signature reported by the explorer
+    pub function_name: Option<String>,
+    /// Raw calldata of the transaction
+    pub input: Bytes,
+    /// Calldata decoded against a known contract ABI
+    pub decoded: Option<DecodedCall>,
+    /// Method name guessed from the function selector when no ABI is known
+    pub guessed_method: Option<String>,
+    /// Every known signature sharing the selector when the guess is ambiguous
+    pub method_candidates: Vec<String>,
+    /// Event logs decoded from the transaction receipt
+    pub events: Vec<DecodedEvent>,
     /// Optional category for the transaction
     pub category: Option<String>,
     /// Optional description for the transaction
     pub description: Option<String>,
+    /// Tagged name of the address on the other side of the transaction
+    pub counterparty: Option<String>,
+    /// Optional GnuCash account overriding the category as the export account
+    pub account: Option<String>,
+    /// GnuCash accounts for individual splits keyed by commodity
+    pub split_accounts: HashMap<String, String>,
     /// ERC-20 token transfers associated with this transaction
     pub transfers: Vec<Erc20Transfer>,
+    /// ERC-721 and ERC-1155 transfers associated with this transaction
+    pub nft_transfers: Vec<NftTransfer>,
+    /// Fee paid by the sender, read from the transaction receipt
+    pub fee: Option<Fee>,
+    /// Gas used times gas price as listed by the explorer, in wei
+    #[serde(default)]
+    pub gas_cost: U256,
+    /// The address did not sign this transaction and only takes part through
+    /// the token or NFT transfers. `from` and `to` are then those of the first
+    /// transfer.
+    #[serde(default)]
+    pub transfer_only: bool,
 }
 
 /// Details for a single ERC-20 token transfer
-#[derive(Clone, Debug, serde::Serialize)]
+#[derive(Clone, Debug, serde::Serialize, Deserialize)]
 pub struct Erc20Transfer {
     pub token_contract: Address,
     pub from: Address,
@@ -93,6 +238,41 @@ pub struct Erc20Transfer {
     pub token_name: String,
     pub token_symbol: String,
     pub token_decimal: String,
+    /// Tagged name of the other party of the transfer
+    #[serde(default)]
+    pub counterparty: Option<String>,
+}
+
+/// Token standard of an NFT collection
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, Deserialize)]
+pub enum NftStandard {
+    Erc721,
+    Erc1155,
+}
+
+/// Details for a single ERC-721 or ERC-1155 token transfer
+#[derive(Clone, Debug, serde::Serialize, Deserialize)]
+pub struct NftTransfer {
+    pub standard: NftStandard,
+    pub contract: Address,
+    pub from: Address,
+    pub to: Option<Address>,
+    pub token_id: String,
+    /// Number of tokens moved, always one for ERC-721
+    pub amount: U256,
+    pub collection_name: String,
+    pub collection_symbol: String,
+}
+
+impl NftTransfer {
+    /// Return the commodity shared by every token of the collection.
+    pub fn commodity(&self) -> String {
+        if self.collection_symbol.is_empty() {
+            format!("NFT {:#x}", self.contract)
+        } else {
+            format!("NFT {}", self.collection_symbol)
+        }
+    }
 }
 
 /// Category information associated with an address
@@ -152,6 +332,18 @@ impl Categories {
     }
 }
 
+/// Read `path` and deserialize it as TOML, JSON or YAML depending on the file
+/// extension, defaulting to YAML.
+pub(crate) fn load_structured<T: DeserializeOwned>(path: &Path) -> Result<T, Box<dyn Error>> {
+    let contents = fs::read_to_string(path)?;
+    let value = match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => serde_json::from_str(&contents)?,
+        Some("toml") => toml::from_str(&contents)?,
+        _ => serde_yaml::from_str(&contents)?,
+    };
+    Ok(value)
+}
+
 fn group_transfers(events: Vec<ERC20TokenTransferEvent>) -> HashMap<H256, Vec<Erc20Transfer>> {
     let mut map: HashMap<H256, Vec<Erc20Transfer>> = HashMap::new();
     for ev in events {
@@ -163,6 +355,68 @@ fn group_transfers(events: Vec<ERC20TokenTransferEvent>) -> HashMap<H256, Vec<Er
             token_name: ev.token_name,
             token_symbol: ev.token_symbol,
             token_decimal: ev.token_decimal,
+            counterparty: None,
+        };
+        map.entry(ev.hash).or_default().push(transfer);
+    }
+    map
+}
+
+/// Block, timestamp and parties of the first transfer seen in a transaction
+struct TransferHeader {
+    block_number: u64,
+    timestamp: u64,
+    from: Address,
+    to: Option<Address>,
+}
+
+impl TransferHeader {
+    fn new(
+        block_number: &BlockNumber,
+        time_stamp: &str,
+        from: Address,
+        to: Option<Address>,
+    ) -> Self {
+        Self {
+            block_number: block_number
+                .as_number()
+                .map(|n| n.as_u64())
+                .unwrap_or_default(),
+            timestamp: time_stamp.parse().unwrap_or_default(),
+            from,
+            to,
+        }
+    }
+}
+
+fn group_nft_transfers(
+    erc721: Vec<ERC721TokenTransferEvent>,
+    erc1155: Vec<ERC1155TokenTransferEvent>,
+) -> HashMap<H256, Vec<NftTransfer>> {
+    let mut map: HashMap<H256, Vec<NftTransfer>> = HashMap::new();
+    for ev in erc721 {
+        let transfer = NftTransfer {
+            standard: NftStandard::Erc721,
+            contract: ev.contract_address,
+            from: ev.from,
+            to: ev.to,
+            token_id: ev.token_id,
+            amount: U256::one(),
+            collection_name: ev.token_name,
+            collection_symbol: ev.token_symbol,
+        };
+        map.entry(ev.hash).or_default().push(transfer);
+    }
+    for ev in erc1155 {
+        let transfer = NftTransfer {
+            standard: NftStandard::Erc1155,
+            contract: ev.contract_address,
+            from: ev.from,
+            to: ev.to,
+            token_id: ev.token_id,
+            amount: U256::from_dec_str(&ev.token_value).unwrap_or_else(|_| U256::one()),
+            collection_name: ev.token_name,
+            collection_symbol: ev.token_symbol,
         };
         map.entry(ev.hash).or_default().push(transfer);
     }
@@ -202,6 +456,27 @@ pub trait TxSource {
         option: TokenQueryOption,
         params: Option<TxListParams>,
     ) -> Result<Vec<ERC20TokenTransferEvent>, Box<dyn Error>>;
+
+    async fn get_erc721_token_transfer_events(
+        &self,
+        option: TokenQueryOption,
+        params: Option<TxListParams>,
+    ) -> Result<Vec<ERC721TokenTransferEvent>, Box<dyn Error>>;
+
+    async fn get_erc1155_token_transfer_events(
+        &self,
+        option: TokenQueryOption,
+        params: Option<TxListParams>,
+    ) -> Result<Vec<ERC1155TokenTransferEvent>, Box<dyn Error>>;
+
+    async fn get_internal_transactions(
+        &self,
+        option: InternalTxQueryOption,
+        params: Option<TxListParams>,
+    ) -> Result<Vec<InternalTransaction>, Box<dyn Error>>;
+
+    /// Return the number of the newest block mined at or before `timestamp`.
+    async fn get_block_number_by_timestamp(&self, timestamp: u64) -> Result<u64, Box<dyn Error>>;
 }
 
 #[async_trait]
@@ -221,50 +496,366 @@ impl TxSource for EtherscanClient {
     ) -> Result<Vec<ERC20TokenTransferEvent>, Box<dyn Error>> {
         Ok(EtherscanClient::get_erc20_token_transfer_events(self, option, params).await?)
     }
+
+    async fn get_erc721_token_transfer_events(
+        &self,
+        option: TokenQueryOption,
+        params: Option<TxListParams>,
+    ) -> Result<Vec<ERC721TokenTransferEvent>, Box<dyn Error>> {
+        Ok(EtherscanClient::get_erc721_token_transfer_events(self, option, params).await?)
+    }
+
+    async fn get_erc1155_token_transfer_events(
+        &self,
+        option: TokenQueryOption,
+        params: Option<TxListParams>,
+    ) -> Result<Vec<ERC1155TokenTransferEvent>, Box<dyn Error>> {
+        Ok(EtherscanClient::get_erc1155_token_transfer_events(self, option, params).await?)
+    }
+
+    async fn get_internal_transactions(
+        &self,
+        option: InternalTxQueryOption,
+        params: Option<TxListParams>,
+    ) -> Result<Vec<InternalTransaction>, Box<dyn Error>> {
+        Ok(EtherscanClient::get_internal_transactions(self, option, params).await?)
+    }
+
+    async fn get_block_number_by_timestamp(&self, timestamp: u64) -> Result<u64, Box<dyn Error>> {
+        let res = EtherscanClient::get_block_by_timestamp(self, timestamp, "before").await?;
+        Ok(res
+            .block_number
+            .as_number()
+            .map(|n| n.as_u64())
+            .unwrap_or_default())
+    }
 }
 
-/// Retrieve all normal transactions for the given address using the provided [`EtherscanClient`].
-pub async fn fetch_transactions<C>(
+/// Trait abstracting the read-only RPC calls used to inspect on-chain state.
+#[async_trait]
+pub trait ChainState {
+    /// Return the native ETH balance of `address` at `block`, or at the latest
+    /// block if `block` is `None`.
+    async fn get_balance(
+        &self,
+        address: Address,
+        block: Option<u64>,
+    ) -> Result<U256, Box<dyn Error>>;
+
+    /// Execute a read-only `eth_call` of `data` against contract `to`.
+    async fn call(
+        &self,
+        to: Address,
+        data: Bytes,
+        block: Option<u64>,
+    ) -> Result<Bytes, Box<dyn Error>>;
+}
+
+#[async_trait]
+impl ChainState for Provider<Http> {
+    async fn get_balance(
+        &self,
+        address: Address,
+        block: Option<u64>,
+    ) -> Result<U256, Box<dyn Error>> {
+        let block = block.map(BlockId::from);
+        Ok(Middleware::get_balance(self, address, block).await?)
+    }
+
+    async fn call(
+        &self,
+        to: Address,
+        data: Bytes,
+        block: Option<u64>,
+    ) -> Result<Bytes, Box<dyn Error>> {
+        let tx = TransactionRequest::new().to(to).data(data).into();
+        let block = block.map(BlockId::from);
+        Ok(Middleware::call(self, &tx, block).await?)
+    }
+}
+
+/// Trait abstracting how transaction receipts are retrieved.
+#[async_trait]
+pub trait ReceiptSource {
+    async fn get_receipt(&self, hash: H256) -> Result<Option<TransactionReceipt>, Box<dyn Error>>;
+}
+
+#[async_trait]
+impl ReceiptSource for Provider<Http> {
+    async fn get_receipt(&self, hash: H256) -> Result<Option<TransactionReceipt>, Box<dyn Error>> {
+        Ok(Middleware::get_transaction_receipt(self, hash).await?)
+    }
+}
+
+/// Receipts already fetched, keyed by transaction hash.
+pub type Receipts = HashMap<H256, TransactionReceipt>;
+
+#[async_trait]
+impl ReceiptSource for Receipts {
+    async fn get_receipt(&self, hash: H256) -> Result<Option<TransactionReceipt>, Box<dyn Error>> {
+        Ok(self.get(&hash).cloned())
+    }
+}
+
+/// Fetch the receipt of every transaction in `hashes` once, skipping duplicates.
+pub async fn fetch_receipts<R>(
+    source: &R,
+    hashes: impl IntoIterator<Item = H256>,
+) -> Result<Receipts, Box<dyn Error>>
+where
+    R: ReceiptSource + Sync,
+{
+    let mut receipts = Receipts::new();
+    for hash in hashes {
+        if receipts.contains_key(&hash) {
+            continue;
+        }
+        if let Some(receipt) = source.get_receipt(hash).await? {
+            receipts.insert(hash, receipt);
+        }
+    }
+    Ok(receipts)
+}
+
+/// Read a single `uint256` result from a contract call of `selector` with `args`.
+async fn call_uint<S>(
+    state: &S,
+    contract: Address,
+    selector: [u8; 4],
+    args: &[Token],
+    block: Option<u64>,
+) -> Result<U256, Box<dyn Error>>
+where
+    S: ChainState + Sync,
+{
+    let mut data = selector.to_vec();
+    data.extend(abi::encode(args));
+    let out = state.call(contract, data.into(), block).await?;
+    if out.len() < 32 {
+        return Err(format!("unexpected return data from {:#x}", contract).into());
+    }
+    Ok(U256::from_big_endian(&out[..32]))
+}
+
+/// Query the ERC-20 `balanceOf(owner)` of `token` at `block`.
+pub async fn token_balance<S>(
+    state: &S,
+    token: Address,
+    owner: Address,
+    block: Option<u64>,
+) -> Result<U256, Box<dyn Error>>
+where
+    S: ChainState + Sync,
+{
+    call_uint(
+        state,
+        token,
+        [0x70, 0xa0, 0x82, 0x31],
+        &[Token::Address(owner)],
+        block,
+    )
+    .await
+}
+
+/// Query the ERC-20 `decimals()` of `token`.
+pub async fn token_decimals<S>(state: &S, token: Address) -> Result<u32, Box<dyn Error>>
+where
+    S: ChainState + Sync,
+{
+    let decimals = call_uint(state, token, [0x31, 0x3c, 0xe5, 0x67], &[], None).await?;
+    Ok(decimals.low_u32())
+}
+
+/// Return the last block mined on or before the end of `date` (UTC).
+pub async fn block_at_end_of<C>(client: &C, date: NaiveDate) -> Result<u64, Box<dyn Error>>
+where
+    C: TxSource + Sync,
+{
+    block_at_end_of_in(client, date, Tz::UTC).await
+}
+
+/// Return the last block mined on or before the end of `date` in `tz`.
+pub async fn block_at_end_of_in<C>(
     client: &C,
-    address: Address,
-) -> Result<Vec<Transaction>, Box<dyn Error>>
+    date: NaiveDate,
+    tz: Tz,
+) -> Result<u64, Box<dyn Error>>
 where
     C: TxSource + Sync,
+{
+    client
+        .get_block_number_by_timestamp(end_of_day(date, tz))
+        .await
+}
+
+/// Return the timestamp of the last second of `date` in `tz`.
+pub fn end_of_day(date: NaiveDate, tz: Tz) -> u64 {
+    let end_of_day = date.and_hms_opt(23, 59, 59).unwrap_or_default();
+    let end_of_day = match tz.from_local_datetime(&end_of_day) {
+        LocalResult::Single(t) | LocalResult::Ambiguous(_, t) => t.timestamp(),
+        LocalResult::None => end_of_day.and_utc().timestamp(),
+    };
+    end_of_day as u64
+}
+
+/// Inclusive range of block numbers to fetch
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, Deserialize)]
+pub struct BlockRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Default for BlockRange {
+    /// Every block. The explorer's own default end block of 99,999,999 lies in
+    /// the past on Arbitrum.
+    fn default() -> Self {
+        Self {
+            start: 0,
+            end: 9_999_999_999,
+        }
+    }
+}
+
+impl BlockRange {
+    /// Return true if the range has no upper bound.
+    pub fn is_open(&self) -> bool {
+        self.end >= Self::default().end
+    }
+}
+
+/// Translate optional dates and block numbers into a block range. `from` and
+/// `to` are inclusive dates in `tz` and are combined with explicit block numbers
+/// by keeping the narrower bound.
+pub async fn block_range<C>(
+    client: &C,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    start_block: Option<u64>,
+    end_block: Option<u64>,
+    tz: Tz,
+) -> Result<BlockRange, Box<dyn Error>>
+where
+    C: TxSource + Sync,
+{
+    if let (Some(from), Some(to)) = (from, to) {
+        if from > to {
+            return Err(format!("--from {} lies after --to {}", from, to).into());
+        }
+    }
+    if let (Some(start), Some(end)) = (start_block, end_block) {
+        if start > end {
+            return Err(format!("--start-block {} lies after --end-block {}", start, end).into());
+        }
+    }
+    let mut range = BlockRange::default();
+    if let Some(date) = from.and_then(|d| d.pred_opt()) {
+        range.start = block_at_end_of_in(client, date, tz).await? + 1;
+    }
+    if let Some(date) = to {
+        range.end = block_at_end_of_in(client, date, tz).await?;
+    }
+    range.start = range.start.max(start_block.unwrap_or_default());
+    range.end = range.end.min(end_block.unwrap_or(u64::MAX));
+    if range.start > range.end {
+        return Err(format!(
+            "the dates and blocks given leave no blocks to fetch ({} to {})",
+            range.start, range.end
+        )
+        .into());
+    }
+    Ok(range)
+}
+
+/// Request pages of 100 entries within `range` from `fetch` until an empty page
+/// is returned.
+async fn fetch_pages<T, F, Fut>(range: BlockRange, mut fetch: F) -> Result<Vec<T>, Box<dyn Error>>
+where
+    F: FnMut(TxListParams) -> Fut,
+    Fut: Future<Output = Result<Vec<T>, Box<dyn Error>>>,
 {
     let mut page = 1u64;
-    let mut txs = Vec::new();
+    let mut res = Vec::new();
     loop {
         let params = TxListParams {
+            start_block: range.start,
+            end_block: range.end,
             page,
             offset: 100,
             ..Default::default()
         };
-        let mut batch = client.get_transactions(&address, Some(params)).await?;
+        let mut batch = fetch(params).await?;
         if batch.is_empty() {
             break;
         }
-        txs.append(&mut batch);
+        res.append(&mut batch);
         page += 1;
     }
+    Ok(res)
+}
 
-    page = 1;
-    let mut events_all = Vec::new();
-    loop {
-        let params = TxListParams {
-            page,
-            offset: 100,
-            ..Default::default()
-        };
-        let mut ev = client
-            .get_erc20_token_transfer_events(TokenQueryOption::ByAddress(address), Some(params))
-            .await?;
-        if ev.is_empty() {
-            break;
-        }
-        events_all.append(&mut ev);
-        page += 1;
+/// Retrieve all normal transactions for the given address using the provided [`EtherscanClient`].
+pub async fn fetch_transactions<C>(
+    client: &C,
+    address: Address,
+) -> Result<Vec<Transaction>, Box<dyn Error>>
+where
+    C: TxSource + Sync,
+{
+    fetch_transactions_range(client, address, BlockRange::default()).await
+}
+
+/// Retrieve the normal transactions of the given address mined within `range`.
+pub async fn fetch_transactions_range<C>(
+    client: &C,
+    address: Address,
+    range: BlockRange,
+) -> Result<Vec<Transaction>, Box<dyn Error>>
+where
+    C: TxSource + Sync,
+{
+    let txs = fetch_pages(range, |params| {
+        client.get_transactions(&address, Some(params))
+    })
+    .await?;
+    let query = || TokenQueryOption::ByAddress(address);
+    let events_all = fetch_pages(range, |params| {
+        client.get_erc20_token_transfer_events(query(), Some(params))
+    })
+    .await?;
+    let erc721 = fetch_pages(range, |params| {
+        client.get_erc721_token_transfer_events(query(), Some(params))
+    })
+    .await?;
+    let erc1155 = fetch_pages(range, |params| {
+        client.get_erc1155_token_transfer_events(query(), Some(params))
+    })
+    .await?;
+    let mut headers: HashMap<H256, TransferHeader> = HashMap::new();
+    let all_headers = events_all
+        .iter()
+        .map(|ev| {
+            (
+                ev.hash,
+                TransferHeader::new(&ev.block_number, &ev.time_stamp, ev.from, ev.to),
+            )
+        })
+        .chain(erc721.iter().map(|ev| {
+            (
+                ev.hash,
+                TransferHeader::new(&ev.block_number, &ev.time_stamp, ev.from, ev.to),
+            )
+        }))
+        .chain(erc1155.iter().map(|ev| {
+            (
+                ev.hash,
+                TransferHeader::new(&ev.block_number, &ev.time_stamp, ev.from, ev.to),
+            )
+        }));
+    for (hash, header) in all_headers {
+        headers.entry(hash).or_insert(header);
     }
     let mut transfers = group_transfers(events_all);
+    let mut nft_transfers = group_nft_transfers(erc721, erc1155);
     let mut result = Vec::new();
 
     for tx in txs {
@@ -291,26 +882,124 @@ where
             from,
             to: tx.to,
             value: tx.value,
+            method_id: tx.method_id.filter(|m| !m.is_empty()),
+            function_name: tx.function_name.filter(|f| !f.is_empty()),
+            input: tx.input,
+            decoded: None,
+            guessed_method: None,
+            method_candidates: Vec::new(),
+            events: Vec::new(),
             category: None,
             description: None,
+            counterparty: None,
+            account: None,
+            split_accounts: HashMap::new(),
             transfers: transfers.remove(&hash).unwrap_or_default(),
+            nft_transfers: nft_transfers.remove(&hash).unwrap_or_default(),
+            fee: None,
+            gas_cost: tx.gas_used * tx.gas_price.unwrap_or_default(),
+            transfer_only: false,
         });
     }
 
+    // Transfers left over belong to transactions signed by someone else, such
+    // as NFT sales executed by the buyer, gifts and airdrops.
+    let mut others: Vec<Transaction> = headers
+        .into_iter()
+        .filter_map(|(hash, header)| {
+            let tokens = transfers.remove(&hash);
+            let nfts = nft_transfers.remove(&hash);
+            if tokens.is_none() && nfts.is_none() {
+                return None;
+            }
+            Some(Transaction {
+                hash,
+                block_number: header.block_number,
+                timestamp: header.timestamp,
+                from: header.from,
+                to: header.to,
+                transfers: tokens.unwrap_or_default(),
+                nft_transfers: nfts.unwrap_or_default(),
+                transfer_only: true,
+                ..Default::default()
+            })
+        })
+        .collect();
+    others.sort_by_key(|tx| tx.hash);
+    result.extend(others);
+    result.sort_by_key(|tx| tx.block_number);
+
     Ok(result)
 }
 
+/// ETH moved by a contract call within a transaction
+#[derive(Clone, Debug, Default, serde::Serialize, Deserialize)]
+pub struct InternalTransfer {
+    pub hash: H256,
+    pub block_number: u64,
+    pub timestamp: u64,
+    pub from: Address,
+    pub to: Option<Address>,
+    pub value: U256,
+}
+
+/// Retrieve the internal ETH transfers to or from `address`, skipping failed
+/// calls.
+pub async fn fetch_internal_transfers<C>(
+    client: &C,
+    address: Address,
+) -> Result<Vec<InternalTransfer>, Box<dyn Error>>
+where
+    C: TxSource + Sync,
+{
+    let calls = fetch_pages(BlockRange::default(), |params| {
+        client.get_internal_transactions(InternalTxQueryOption::ByAddress(address), Some(params))
+    })
+    .await?;
+    Ok(calls
+        .into_iter()
+        .filter(|call| call.is_error == "0" && !call.value.is_zero())
+        .map(|call| InternalTransfer {
+            hash: call.hash,
+            block_number: call
+                .block_number
+                .as_number()
+                .map(|n| n.as_u64())
+                .unwrap_or_default(),
+            timestamp: call.time_stamp.parse().unwrap_or_default(),
+            from: call.from,
+            to: call.to.into(),
+            value: call.value,
+        })
+        .collect())
+}
+
+/// Mock explorer and chain state shared by the tests of every module.
 #[cfg(test)]
-mod tests {
+pub(crate) mod test_util {
     use super::*;
-
     use ethers::etherscan::account::GenesisOption;
-    use ethers::etherscan::Client as EtherscanClient;
-    use ethers::types::{BlockNumber, Bytes};
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
-    struct MockClient {
-        tx_pages: Vec<Vec<NormalTransaction>>,
-        event_pages: Vec<Vec<ERC20TokenTransferEvent>>,
+    /// Explorer serving pages of transactions and token transfers. Blocks are
+    /// mined every `block_time` seconds.
+    pub(crate) struct MockClient {
+        pub(crate) tx_pages: Vec<Vec<NormalTransaction>>,
+        pub(crate) event_pages: Vec<Vec<ERC20TokenTransferEvent>>,
+        pub(crate) nft_pages: Vec<Vec<ERC721TokenTransferEvent>>,
+        pub(crate) block_time: u64,
+    }
+
+    impl MockClient {
+        /// Return a client without any transactions.
+        pub(crate) fn with_block_time(block_time: u64) -> Self {
+            Self {
+                tx_pages: Vec::new(),
+                event_pages: Vec::new(),
+                nft_pages: Vec::new(),
+                block_time,
+            }
+        }
     }
 
     #[async_trait]
@@ -320,8 +1009,16 @@ mod tests {
             _address: &Address,
             params: Option<TxListParams>,
         ) -> Result<Vec<NormalTransaction>, Box<dyn Error>> {
-            let page = params.map(|p| p.page).unwrap_or(1) as usize;
-            Ok(self.tx_pages.get(page - 1).cloned().unwrap_or_default())
+            let params = params.unwrap_or_default();
+            let page = params.page.max(1) as usize;
+            let txs = self.tx_pages.get(page - 1).cloned().unwrap_or_default();
+            Ok(txs
+                .into_iter()
+                .filter(|tx| {
+                    let block = tx.block_number.as_number().unwrap_or_default().as_u64();
+                    (params.start_block..=params.end_block).contains(&block)
+                })
+                .collect())
         }
 
         async fn get_erc20_token_transfer_events(
@@ -329,12 +1026,115 @@ mod tests {
             _option: TokenQueryOption,
             params: Option<TxListParams>,
         ) -> Result<Vec<ERC20TokenTransferEvent>, Box<dyn Error>> {
-            let page = params.map(|p| p.page).unwrap_or(1) as usize;
+            let page = params.map(|p| p.page).unwrap_or(1).max(1) as usize;
             Ok(self.event_pages.get(page - 1).cloned().unwrap_or_default())
         }
+
+        async fn get_erc721_token_transfer_events(
+            &self,
+            _option: TokenQueryOption,
+            params: Option<TxListParams>,
+        ) -> Result<Vec<ERC721TokenTransferEvent>, Box<dyn Error>> {
+            let page = params.map(|p| p.page).unwrap_or(1).max(1) as usize;
+            Ok(self.nft_pages.get(page - 1).cloned().unwrap_or_default())
+        }
+
+        async fn get_erc1155_token_transfer_events(
+            &self,
+            _option: TokenQueryOption,
+            _params: Option<TxListParams>,
+        ) -> Result<Vec<ERC1155TokenTransferEvent>, Box<dyn Error>> {
+            Ok(Vec::new())
+        }
+
+        async fn get_internal_transactions(
+            &self,
+            _option: InternalTxQueryOption,
+            _params: Option<TxListParams>,
+        ) -> Result<Vec<InternalTransaction>, Box<dyn Error>> {
+            Ok(Vec::new())
+        }
+
+        async fn get_block_number_by_timestamp(
+            &self,
+            timestamp: u64,
+        ) -> Result<u64, Box<dyn Error>> {
+            Ok(timestamp / self.block_time)
+        }
     }
 
-    fn make_tx(hash: H256) -> NormalTransaction {
+    type BalanceFn = dyn Fn(Address, Option<u64>) -> U256 + Send + Sync;
+
+    /// Chain answering ETH and token balances from a function of the owner and
+    /// block, and the metadata of the registered tokens. Calls to other
+    /// contracts revert.
+    pub(crate) struct MockState {
+        balance: Box<BalanceFn>,
+        /// Name, symbol and decimals by token contract. Names are encoded as
+        /// `bytes32` like some older tokens do.
+        pub(crate) tokens: HashMap<Address, (&'static str, &'static str, u8)>,
+        /// Number of contract calls made
+        pub(crate) calls: AtomicUsize,
+    }
+
+    impl MockState {
+        pub(crate) fn new(
+            balance: impl Fn(Address, Option<u64>) -> U256 + Send + Sync + 'static,
+        ) -> Self {
+            Self {
+                balance: Box::new(balance),
+                tokens: HashMap::new(),
+                calls: AtomicUsize::new(0),
+            }
+        }
+
+        /// Return a chain where every owner holds `balance` at every block.
+        pub(crate) fn constant(balance: U256) -> Self {
+            Self::new(move |_, _| balance)
+        }
+
+        pub(crate) fn calls(&self) -> usize {
+            self.calls.load(Ordering::Relaxed)
+        }
+    }
+
+    #[async_trait]
+    impl ChainState for MockState {
+        async fn get_balance(
+            &self,
+            address: Address,
+            block: Option<u64>,
+        ) -> Result<U256, Box<dyn Error>> {
+            Ok((self.balance)(address, block))
+        }
+
+        async fn call(
+            &self,
+            to: Address,
+            data: Bytes,
+            block: Option<u64>,
+        ) -> Result<Bytes, Box<dyn Error>> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            if data[..4] == [0x70, 0xa0, 0x82, 0x31] {
+                let owner = Address::from_slice(&data[16..36]);
+                return Ok(abi::encode(&[Token::Uint((self.balance)(owner, block))]).into());
+            }
+            let (name, symbol, decimals) = self.tokens.get(&to).ok_or("execution reverted")?;
+            let out = match data[..4] {
+                [0x31, 0x3c, 0xe5, 0x67] => abi::encode(&[Token::Uint(U256::from(*decimals))]),
+                [0x95, 0xd8, 0x9b, 0x41] => abi::encode(&[Token::String(symbol.to_string())]),
+                _ => {
+                    let mut name = name.as_bytes().to_vec();
+                    name.resize(32, 0);
+                    name
+                }
+            };
+            Ok(out.into())
+        }
+    }
+
+    /// Return a successful transaction sent by `from` in block 1.
+    pub(crate) fn normal_tx(hash: H256, from: Address) -> NormalTransaction {
         NormalTransaction {
             is_error: "0".to_string(),
             block_number: BlockNumber::Number(1u64.into()),
@@ -343,7 +1143,7 @@ mod tests {
             nonce: None,
             block_hash: None,
             transaction_index: None,
-            from: GenesisOption::Some(Address::zero()),
+            from: GenesisOption::Some(from),
             to: Some(Address::zero()),
             value: U256::zero(),
             gas: U256::zero(),
@@ -359,17 +1159,24 @@ mod tests {
         }
     }
 
-    fn make_event(hash: H256) -> ERC20TokenTransferEvent {
+    /// Return a transfer of `value` units of an 18-decimals token in block 1.
+    pub(crate) fn transfer_event(
+        hash: H256,
+        token: Address,
+        from: Address,
+        to: Address,
+        value: U256,
+    ) -> ERC20TokenTransferEvent {
         ERC20TokenTransferEvent {
             block_number: BlockNumber::Number(1u64.into()),
             time_stamp: "1".to_string(),
             hash,
             nonce: U256::zero(),
             block_hash: H256::zero(),
-            from: Address::zero(),
-            contract_address: Address::zero(),
-            to: Some(Address::zero()),
-            value: U256::one(),
+            from,
+            contract_address: token,
+            to: Some(to),
+            value,
             token_name: "T".to_string(),
             token_symbol: "T".to_string(),
             token_decimal: "18".to_string(),
@@ -382,6 +1189,43 @@ mod tests {
             confirmations: 0,
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_util::{normal_tx, transfer_event, MockClient, MockState};
+    use super::*;
+
+    use ethers::etherscan::Client as EtherscanClient;
+
+    fn make_event(hash: H256) -> ERC20TokenTransferEvent {
+        let zero = Address::zero();
+        transfer_event(hash, zero, zero, zero, U256::one())
+    }
+
+    fn make_nft_event(hash: H256) -> ERC721TokenTransferEvent {
+        ERC721TokenTransferEvent {
+            block_number: BlockNumber::Number(1u64.into()),
+            time_stamp: "1".to_string(),
+            hash,
+            nonce: U256::zero(),
+            block_hash: H256::zero(),
+            from: Address::zero(),
+            contract_address: Address::repeat_byte(0x77),
+            to: Some(Address::zero()),
+            token_id: "7".to_string(),
+            token_name: "Uniswap V3 Positions NFT-V1".to_string(),
+            token_symbol: "UNI-V3-POS".to_string(),
+            token_decimal: "0".to_string(),
+            transaction_index: 0,
+            gas: U256::zero(),
+            gas_price: None,
+            gas_used: U256::zero(),
+            cumulative_gas_used: U256::zero(),
+            input: String::new(),
+            confirmations: 0,
+        }
+    }
 
     #[test]
     fn transaction_with_transfer() {
@@ -393,6 +1237,7 @@ mod tests {
             token_name: "TEST".to_string(),
             token_symbol: "TST".to_string(),
             token_decimal: "18".to_string(),
+            counterparty: None,
         };
 
         let tx = Transaction {
@@ -405,6 +1250,7 @@ mod tests {
             category: None,
             description: None,
             transfers: vec![transfer.clone()],
+            ..Default::default()
         };
 
         assert_eq!(tx.transfers.len(), 1);
@@ -423,6 +1269,7 @@ mod tests {
             category: None,
             description: None,
             transfers: Vec::new(),
+            ..Default::default()
         }];
 
         let mut map = HashMap::new();
@@ -466,11 +1313,11 @@ mod tests {
 
     #[tokio::test]
     async fn fetch_transactions_paginates() {
-        let tx1 = make_tx(H256::from_low_u64_be(1));
-        let tx2 = make_tx(H256::from_low_u64_be(2));
+        let tx1 = normal_tx(H256::from_low_u64_be(1), Address::zero());
+        let tx2 = normal_tx(H256::from_low_u64_be(2), Address::zero());
         let mock = MockClient {
             tx_pages: vec![vec![tx1], vec![tx2]],
-            event_pages: vec![],
+            ..MockClient::with_block_time(10)
         };
 
         let res = fetch_transactions(&mock, Address::zero()).await.unwrap();
@@ -482,22 +1329,195 @@ mod tests {
     #[tokio::test]
     async fn fetch_transactions_paginates_events() {
         let hash = H256::from_low_u64_be(1);
-        let tx = make_tx(hash);
+        let tx = normal_tx(hash, Address::zero());
         let ev1 = make_event(hash);
         let mut ev2 = make_event(hash);
         ev2.value = U256::from(2u64);
         let mock = MockClient {
             tx_pages: vec![vec![tx]],
             event_pages: vec![vec![ev1], vec![ev2]],
+            nft_pages: vec![vec![make_nft_event(hash)]],
+            block_time: 10,
         };
 
         let res = fetch_transactions(&mock, Address::zero()).await.unwrap();
         assert_eq!(res[0].transfers.len(), 2);
+        assert_eq!(res[0].nft_transfers.len(), 1);
+        assert_eq!(res[0].nft_transfers[0].token_id, "7");
+        assert_eq!(res[0].nft_transfers[0].commodity(), "NFT UNI-V3-POS");
     }
+
+    #[tokio::test]
+    async fn transfers_signed_by_others_become_transactions() {
+        let me = Address::repeat_byte(0x11);
+        let buyer = Address::repeat_byte(0x22);
+        let own = H256::from_low_u64_be(1);
+        let sale = H256::from_low_u64_be(2);
+        let mut airdrop = make_event(H256::from_low_u64_be(3));
+        airdrop.to = Some(me);
+        airdrop.block_number = BlockNumber::Number(5u64.into());
+        airdrop.time_stamp = "50".to_string();
+        let mut nft = make_nft_event(sale);
+        nft.from = me;
+        nft.to = Some(buyer);
+        nft.block_number = BlockNumber::Number(3u64.into());
+        let mock = MockClient {
+            tx_pages: vec![vec![normal_tx(own, Address::zero())]],
+            event_pages: vec![vec![make_event(own), airdrop]],
+            nft_pages: vec![vec![nft]],
+            block_time: 10,
+        };
+
+        let res = fetch_transactions(&mock, me).await.unwrap();
+        let hashes: Vec<H256> = res.iter().map(|tx| tx.hash).collect();
+        assert_eq!(hashes, [own, sale, H256::from_low_u64_be(3)]);
+        assert!(!res[0].transfer_only);
+        assert_eq!(res[0].transfers.len(), 1);
+        assert!(res[1].transfer_only);
+        assert_eq!((res[1].from, res[1].to), (me, Some(buyer)));
+        assert_eq!(res[1].nft_transfers.len(), 1);
+        assert_eq!((res[2].block_number, res[2].timestamp), (5, 50));
+        assert_eq!(res[2].transfers[0].to, Some(me));
+    }
+
+    #[tokio::test]
+    async fn fetch_transactions_range_filters_blocks() {
+        let mut tx1 = normal_tx(H256::from_low_u64_be(1), Address::zero());
+        tx1.block_number = BlockNumber::Number(8_639u64.into());
+        let mut tx2 = normal_tx(H256::from_low_u64_be(2), Address::zero());
+        tx2.block_number = BlockNumber::Number(8_640u64.into());
+        let mock = MockClient {
+            tx_pages: vec![vec![tx1, tx2]],
+            ..MockClient::with_block_time(10)
+        };
+        let from = NaiveDate::from_ymd_opt(1970, 1, 2);
+        let range = block_range(&mock, from, None, None, Some(10_000), Tz::UTC)
+            .await
+            .unwrap();
+        assert_eq!(
+            range,
+            BlockRange {
+                start: 8_640,
+                end: 10_000
+            }
+        );
+
+        let res = fetch_transactions_range(&mock, Address::zero(), range)
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].hash, H256::from_low_u64_be(2));
+
+        let to = NaiveDate::from_ymd_opt(1970, 1, 1);
+        assert!(block_range(&mock, from, to, None, None, Tz::UTC)
+            .await
+            .is_err());
+        assert!(block_range(&mock, None, None, Some(2), Some(1), Tz::UTC)
+            .await
+            .is_err());
+        assert!(block_range(&mock, from, None, None, Some(100), Tz::UTC)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn block_at_end_of_uses_last_second() {
+        let mock = MockClient::with_block_time(10);
+        let date = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+        let block = block_at_end_of(&mock, date).await.unwrap();
+        assert_eq!(block, 86_399 / 10);
+    }
+
+    struct CountingReceipts(std::sync::atomic::AtomicUsize);
+
+    #[async_trait]
+    impl ReceiptSource for CountingReceipts {
+        async fn get_receipt(
+            &self,
+            hash: H256,
+        ) -> Result<Option<TransactionReceipt>, Box<dyn Error>> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok((hash != H256::zero()).then(|| TransactionReceipt {
+                transaction_hash: hash,
+                ..Default::default()
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_receipts_fetches_each_hash_once() {
+        let source = CountingReceipts(Default::default());
+        let a = H256::repeat_byte(1);
+        let receipts = fetch_receipts(&source, [a, H256::zero(), a]).await.unwrap();
+        assert_eq!(source.0.into_inner(), 2);
+        assert_eq!(receipts.len(), 1);
+        let receipt = receipts.get_receipt(a).await.unwrap().unwrap();
+        assert_eq!(receipt.transaction_hash, a);
+        assert!(receipts.get_receipt(H256::zero()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn token_balance_encodes_owner() {
+        let owner = Address::repeat_byte(0x11);
+        let state = MockState::new(move |address, _| {
+            if address == owner {
+                U256::from(42u64)
+            } else {
+                U256::zero()
+            }
+        });
+        let bal = token_balance(&state, Address::zero(), owner, Some(1))
+            .await
+            .unwrap();
+        assert_eq!(bal, U256::from(42u64));
+        assert_eq!(
+            ChainState::get_balance(&state, owner, None).await.unwrap(),
+            U256::from(42u64)
+        );
+    }
+
     #[test]
     fn config_loads_toml_and_yaml() {
-        let toml_cfg = Config::load(Some("../examples/config.sample.toml")).expect("load toml");
-        let yaml_cfg = Config::load(Some("../examples/config.sample.yml")).expect("load yaml");
+        let toml_cfg = Config::load(Some(Path::new("../examples/config.sample.toml")), None)
+            .expect("load toml");
+        let yaml_cfg = Config::load(Some(Path::new("../examples/config.sample.yml")), None)
+            .expect("load yaml");
         assert_eq!(toml_cfg.rpc_url, yaml_cfg.rpc_url);
+        assert_eq!(toml_cfg.chain, Chain::Arbitrum);
+    }
+
+    #[test]
+    fn config_profiles_override_file_defaults() {
+        let path = env::temp_dir().join("config_profiles_test.toml");
+        fs::write(
+            &path,
+            r#"
+rpc_url = "https://arb1.arbitrum.io/rpc"
+timezone = "Europe/Berlin"
+store = "store"
+
+[merge_groups]
+USDC = ["USDC.e", "aUSDC"]
+
+[profiles.nova]
+chain = "arbitrum_nova"
+store = "store/nova"
+"#,
+        )
+        .unwrap();
+        let cfg = Config::load(Some(&path), Some("nova")).unwrap();
+        let missing = Config::load(Some(&path), Some("missing"));
+        let _ = fs::remove_file(&path);
+        assert_eq!(cfg.chain, Chain::ArbitrumNova);
+        assert_eq!(cfg.store, Some(PathBuf::from("store/nova")));
+        assert_eq!(cfg.timezone.as_deref(), Some("Europe/Berlin"));
+        assert_eq!(cfg.merge_groups.commodity("aUSDC"), "USDC");
+        assert!(missing.is_err());
+
+        let sample = Config::load(
+            Some(Path::new("../examples/config.sample.yml")),
+            Some("nova"),
+        );
+        assert_eq!(sample.unwrap().output, Some(PathBuf::from("nova.csv")));
     }
 }
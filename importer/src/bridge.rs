@@ -0,0 +1,429 @@
+use chrono::{DateTime, FixedOffset};
+use chrono_tz::Tz;
+use ethers::abi::{self, ParamType};
+use ethers::types::{Address, H256, U256};
+use ethers::utils::id;
+use once_cell::sync::Lazy;
+
+use crate::blockchain::{InternalTransfer, Transaction};
+use crate::export::{local_time, value_to_f64, Split};
+use crate::tokens;
+
+/// Account holding bridged funds that left one chain and have not arrived on
+/// the other yet
+pub const FUNDS_IN_TRANSIT: &str = "Assets:Funds in transit";
+
+/// Account paying the L2 gas submitted with token deposits
+pub const BRIDGE_FEES: &str = "Expenses:Bridge Fees";
+
+/// Account paying the gas of bridge transactions signed on Ethereum
+pub const L1_GAS: &str = "Expenses:Fees:Ethereum Gas";
+
+fn addresses(list: &[&str]) -> Vec<Address> {
+    list.iter()
+        .map(|a| a.parse().expect("valid address"))
+        .collect()
+}
+
+/// ArbSys precompile and token gateways on Arbitrum accepting withdrawals
+static L2_WITHDRAWAL_CONTRACTS: Lazy<Vec<Address>> = Lazy::new(|| {
+    addresses(&[
+        "0x0000000000000000000000000000000000000064",
+        "0x5288c571fd7ad117bea99bf60fe0846c4e84f933",
+        "0x09e9222e96e7b4ae2a407b98d48e330053351eee",
+        "0x6c411ad3e74de3e7bd422b94a27770f5b86c623b",
+    ])
+});
+
+/// Delayed inbox and token gateways on Ethereum accepting deposits
+static L1_DEPOSIT_CONTRACTS: Lazy<Vec<Address>> = Lazy::new(|| {
+    addresses(&[
+        "0x4dbd4fc535ac27206064b68ffcf827b0a60bab3f",
+        "0x72ce9c846789fdb6fc1f34ac4ad25dd9ef7031ef",
+        "0xa3a7b6f88361f48403514059f1f16c8e78d60eec",
+        "0xd3b5b60020504bc3489d6949d545893982ba3011",
+    ])
+});
+
+/// Tokens on Arbitrum minted by a token gateway when a deposit from Ethereum is
+/// finalized: USDC.e, DAI, WBTC and WETH
+static GATEWAY_TOKENS: Lazy<Vec<Address>> = Lazy::new(|| {
+    addresses(&[
+        "0xff970a61a04b1ca14834a43f5de4533ebddb5cc8",
+        "0xda10009cbd5d07dd0cecc66161fc93d7c9000da1",
+        "0x2f2a2543b76a4166549f7aab2e75bef0aefc5b0f",
+        "0x82af49447d8a07e3bd95bd0d56f35241523fbab1",
+    ])
+});
+
+/// Bridge on Ethereum holding deposited ETH and releasing it to withdrawals
+static L1_BRIDGE: Lazy<Address> = Lazy::new(|| {
+    "0x8315177ab297ba92a06054ce80a67ed4dbd7ed3a"
+        .parse()
+        .expect("valid address")
+});
+
+/// Outbox on Ethereum executing withdrawals from Arbitrum
+static L1_OUTBOX: Lazy<Address> = Lazy::new(|| {
+    "0x0b9857ae2d4a3dbe74ffe1d7df045bb7f96e4840"
+        .parse()
+        .expect("valid address")
+});
+
+/// Return true if `tx` starts a withdrawal from Arbitrum to Ethereum.
+pub fn is_l2_withdrawal(tx: &Transaction) -> bool {
+    tx.to
+        .is_some_and(|to| L2_WITHDRAWAL_CONTRACTS.contains(&to))
+}
+
+/// Return true if `tx` completes a deposit from Ethereum to `address`. ETH
+/// deposits show up as transfers from the wallet to itself. Token deposits are
+/// finalized by a retryable ticket the wallet does not sign, which mints one of
+/// the [`GATEWAY_TOKENS`] to the wallet. Tokens minted by other bridges or
+/// protocols, such as native USDC, are not deposits from Ethereum.
+pub fn is_l2_deposit(address: Address, tx: &Transaction) -> bool {
+    let eth =
+        tx.from == address && tx.to == Some(address) && tx.input.is_empty() && !tx.value.is_zero();
+    let minted = tx.transfer_only
+        && tx.transfers.iter().all(|tr| tr.from != address)
+        && tx.transfers.iter().any(|tr| {
+            tr.from.is_zero()
+                && tr.to == Some(address)
+                && GATEWAY_TOKENS.contains(&tr.token_contract)
+        });
+    eth || minted
+}
+
+/// Return the ETH value released by an `executeTransaction` call to the outbox.
+fn claimed_value(tx: &Transaction) -> Option<U256> {
+    let selector = id(
+        "executeTransaction(bytes32[],uint256,address,address,uint256,uint256,uint256,uint256,bytes)",
+    );
+    if !tx.input.starts_with(&selector) {
+        return None;
+    }
+    let types = [
+        ParamType::Array(Box::new(ParamType::FixedBytes(32))),
+        ParamType::Uint(256),
+        ParamType::Address,
+        ParamType::Address,
+        ParamType::Uint(256),
+        ParamType::Uint(256),
+        ParamType::Uint(256),
+        ParamType::Uint(256),
+        ParamType::Bytes,
+    ];
+    abi::decode(&types, &tx.input[4..])
+        .ok()?
+        .swap_remove(7)
+        .into_uint()
+}
+
+/// Book one split of the L1 transaction `hash` at `time`, skipping zero amounts.
+fn push_split(
+    res: &mut Vec<Split>,
+    hash: H256,
+    time: DateTime<FixedOffset>,
+    description: &str,
+    (account, commodity, amount): (&str, &str, f64),
+) {
+    if amount != 0.0 {
+        res.push(Split {
+            id: format!("{:#x}", hash),
+            date: time.date_naive(),
+            description: description.to_string(),
+            account: account.to_string(),
+            commodity: commodity.to_string(),
+            amount,
+            memo: String::new(),
+            time: Some(time),
+        });
+    }
+}
+
+/// Convert the bridge transactions of `address` on Ethereum into splits. Deposits
+/// move ETH or tokens to [`FUNDS_IN_TRANSIT`] and claimed withdrawals move them
+/// back out. ETH sent along with token deposits pays for L2 gas and the gas of
+/// transactions the wallet signed is booked to [`L1_GAS`]. Withdrawals claimed by
+/// a relayer are recognized from tokens released by a gateway and from the
+/// `internal` ETH transfers of the bridge. Tokens use the symbol reported by the
+/// L1 explorer and dates the local time of `tz`. Other L1 transactions are
+/// skipped.
+pub fn l1_splits(
+    address: Address,
+    txs: &[Transaction],
+    internal: &[InternalTransfer],
+    tz: Tz,
+) -> Vec<Split> {
+    let mut res = Vec::new();
+    let signed = |tx: &Transaction| tx.from == address && !tx.transfer_only;
+    for tx in txs {
+        let time = local_time(tx.timestamp, tz);
+        let (deposit, claimed) = match tx.to {
+            Some(to) if signed(tx) => (
+                L1_DEPOSIT_CONTRACTS.contains(&to),
+                if to == *L1_OUTBOX {
+                    claimed_value(tx)
+                } else {
+                    None
+                },
+            ),
+            _ => (false, None),
+        };
+        let relayed = tx.transfer_only
+            && tx
+                .transfers
+                .iter()
+                .any(|tr| tr.to == Some(address) && L1_DEPOSIT_CONTRACTS.contains(&tr.from));
+        if !deposit && claimed.is_none() && !relayed {
+            continue;
+        }
+        let description = if deposit {
+            "Bridge deposit to Arbitrum"
+        } else {
+            "Bridge withdrawal claimed from Arbitrum"
+        };
+        let mut split =
+            |leg: (&str, &str, f64)| push_split(&mut res, tx.hash, time, description, leg);
+
+        let mut tokens = 0;
+        for tr in &tx.transfers {
+            let decimals = tr.token_decimal.parse::<u32>().unwrap_or(18);
+            let amount = value_to_f64(tr.value, decimals);
+            let amount = if tr.from == address {
+                -amount
+            } else if tr.to == Some(address) {
+                amount
+            } else {
+                continue;
+            };
+            split((FUNDS_IN_TRANSIT, &tr.token_symbol, amount));
+            tokens += 1;
+        }
+        if relayed {
+            continue;
+        }
+        let eth = -value_to_f64(tx.value, 18);
+        match claimed {
+            Some(value) => split((FUNDS_IN_TRANSIT, "ETH", value_to_f64(value, 18))),
+            None if tokens > 0 => split((BRIDGE_FEES, "ETH", eth)),
+            None => split((FUNDS_IN_TRANSIT, "ETH", eth)),
+        }
+        split((L1_GAS, "ETH", -value_to_f64(tx.gas_cost, 18)));
+    }
+    for call in internal {
+        let own = txs.iter().any(|tx| tx.hash == call.hash && signed(tx));
+        if own || call.from != *L1_BRIDGE || call.to != Some(address) {
+            continue;
+        }
+        push_split(
+            &mut res,
+            call.hash,
+            local_time(call.timestamp, tz),
+            "Bridge withdrawal claimed from Arbitrum",
+            (FUNDS_IN_TRANSIT, "ETH", value_to_f64(call.value, 18)),
+        );
+    }
+    res.sort_by_key(|s| s.time);
+    res
+}
+
+/// Return true if `l2` and `l1` name the same asset on both chains, either as
+/// the same symbol or because the L2 token merges into the L1 symbol, e.g.
+/// `USDC.e` into `USDC`.
+fn same_asset(l2: &str, l1: &str) -> bool {
+    l2 == l1
+        || tokens::GOOD_TOKENS
+            .values()
+            .any(|info| info.symbol == l2 && info.group == Some(l1))
+}
+
+/// Return true if `a` and `b` are the same amount up to the rounding of
+/// converting raw token amounts with different decimals into `f64`.
+fn same_amount(a: f64, b: f64) -> bool {
+    (a - b).abs() <= a.abs().max(b.abs()) * 1e-12
+}
+
+/// Pair the in-transit splits of the Arbitrum export `l2` with those of the
+/// Ethereum export `l1` and note the hash of the other side in each memo. A pair
+/// moves the same amount in opposite directions, leaving one chain no later than
+/// arriving on the other, and the commodities name the same asset.
+pub fn link(l2: &mut [Split], l1: &mut [Split]) {
+    let mut used = vec![false; l1.len()];
+    for a in l2.iter_mut().filter(|s| s.account == FUNDS_IN_TRANSIT) {
+        let found = l1.iter().enumerate().position(|(i, b)| {
+            !used[i]
+                && b.account == FUNDS_IN_TRANSIT
+                && same_asset(&a.commodity, &b.commodity)
+                && same_amount(b.amount, -a.amount)
+                && if a.amount < 0.0 {
+                    a.date <= b.date
+                } else {
+                    b.date <= a.date
+                }
+        });
+        if let Some(i) = found {
+            used[i] = true;
+            a.memo = format!("L1 {}", l1[i].id);
+            l1[i].memo = format!("L2 {}", a.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Erc20Transfer;
+    use chrono::NaiveDate;
+    use ethers::abi::Token;
+
+    fn transit(id: &str, day: u32, commodity: &str, amount: f64) -> Split {
+        Split {
+            id: id.to_string(),
+            date: NaiveDate::from_ymd_opt(2024, 1, day).unwrap(),
+            description: String::new(),
+            account: FUNDS_IN_TRANSIT.to_string(),
+            commodity: commodity.to_string(),
+            amount,
+            memo: String::new(),
+            time: None,
+        }
+    }
+
+    #[test]
+    fn l1_claims_decode_released_value() {
+        let me = Address::repeat_byte(0x11);
+        let mut input = id(
+            "executeTransaction(bytes32[],uint256,address,address,uint256,uint256,uint256,uint256,bytes)",
+        )
+        .to_vec();
+        input.extend(abi::encode(&[
+            Token::Array(vec![]),
+            Token::Uint(U256::zero()),
+            Token::Address(me),
+            Token::Address(me),
+            Token::Uint(U256::zero()),
+            Token::Uint(U256::zero()),
+            Token::Uint(U256::zero()),
+            Token::Uint(U256::exp10(18)),
+            Token::Bytes(vec![]),
+        ]));
+        let txs = vec![
+            Transaction {
+                hash: H256::repeat_byte(1),
+                from: me,
+                to: Some(*L1_OUTBOX),
+                input: input.into(),
+                ..Default::default()
+            },
+            Transaction {
+                hash: H256::repeat_byte(2),
+                from: me,
+                to: Some(L1_DEPOSIT_CONTRACTS[0]),
+                value: U256::exp10(18),
+                gas_cost: U256::exp10(15),
+                ..Default::default()
+            },
+            Transaction {
+                from: me,
+                to: Some(Address::repeat_byte(0x22)),
+                value: U256::exp10(18),
+                ..Default::default()
+            },
+        ];
+        let res = l1_splits(me, &txs, &[], Tz::UTC);
+        let legs: Vec<(&str, f64)> = res.iter().map(|s| (s.account.as_str(), s.amount)).collect();
+        assert_eq!(
+            legs,
+            [
+                (FUNDS_IN_TRANSIT, 1.0),
+                (FUNDS_IN_TRANSIT, -1.0),
+                (L1_GAS, -0.001)
+            ]
+        );
+    }
+
+    #[test]
+    fn l1_claims_executed_by_relayer() {
+        let me = Address::repeat_byte(0x11);
+        let relayer = Address::repeat_byte(0x33);
+        let released = |from: Address| Erc20Transfer {
+            token_contract: Address::repeat_byte(0x44),
+            from,
+            to: Some(me),
+            value: U256::from(100_000_000u64),
+            token_name: "USD Coin".to_string(),
+            token_symbol: "USDC".to_string(),
+            token_decimal: "6".to_string(),
+            counterparty: None,
+        };
+        let txs = vec![
+            Transaction {
+                hash: H256::repeat_byte(1),
+                from: L1_DEPOSIT_CONTRACTS[2],
+                to: Some(me),
+                transfers: vec![released(L1_DEPOSIT_CONTRACTS[2])],
+                gas_cost: U256::exp10(15),
+                transfer_only: true,
+                ..Default::default()
+            },
+            Transaction {
+                hash: H256::repeat_byte(2),
+                from: relayer,
+                to: Some(me),
+                transfers: vec![released(relayer)],
+                transfer_only: true,
+                ..Default::default()
+            },
+        ];
+        let internal = vec![
+            InternalTransfer {
+                hash: H256::repeat_byte(3),
+                from: *L1_BRIDGE,
+                to: Some(me),
+                value: U256::exp10(18),
+                ..Default::default()
+            },
+            InternalTransfer {
+                hash: H256::repeat_byte(4),
+                from: relayer,
+                to: Some(me),
+                value: U256::exp10(18),
+                ..Default::default()
+            },
+        ];
+        let res = l1_splits(me, &txs, &internal, Tz::UTC);
+        let legs: Vec<(&str, f64)> = res
+            .iter()
+            .map(|s| (s.commodity.as_str(), s.amount))
+            .collect();
+        assert_eq!(legs, [("USDC", 100.0), ("ETH", 1.0)]);
+        assert!(res.iter().all(|s| s.account == FUNDS_IN_TRANSIT));
+    }
+
+    #[test]
+    fn link_pairs_opposite_movements() {
+        let mut l2 = vec![
+            transit("l2-withdraw", 2, "ETH", -1.0),
+            transit("l2-deposit", 5, "USDC.e", 100.0),
+            transit("l2-pending", 9, "ETH", -2.0),
+            transit("l2-dai", 6, "DAI", 50.0),
+            transit("l2-rounded", 3, "ETH", -(0.1 + 0.2)),
+        ];
+        let mut l1 = vec![
+            transit("l1-deposit", 4, "USDC", -100.0),
+            transit("l1-claim", 9, "ETH", 1.0),
+            transit("l1-early", 1, "ETH", 2.0),
+            transit("l1-usdc", 5, "USDC", -50.0),
+            transit("l1-rounded", 3, "ETH", 0.3),
+        ];
+        link(&mut l2, &mut l1);
+        assert_eq!(l2[0].memo, "L1 l1-claim");
+        assert_eq!(l2[1].memo, "L1 l1-deposit");
+        assert_eq!(l2[2].memo, "");
+        assert_eq!(l2[3].memo, "");
+        assert_eq!(l1[1].memo, "L2 l2-withdraw");
+        assert_eq!(l1[2].memo, "");
+        assert_eq!(l2[4].memo, "L1 l1-rounded");
+    }
+}
@@ -0,0 +1,203 @@
+use ethers::abi::{Abi, Token};
+use ethers::types::{Address, I256};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::blockchain::Transaction;
+
+/// A single named argument of decoded calldata or event data
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DecodedArg {
+    pub name: String,
+    pub value: String,
+}
+
+/// Function call decoded from transaction input
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DecodedCall {
+    pub name: String,
+    pub signature: String,
+    pub args: Vec<DecodedArg>,
+}
+
+impl DecodedCall {
+    /// Return the value of the argument called `name`.
+    pub fn arg(&self, name: &str) -> Option<&str> {
+        self.args
+            .iter()
+            .find(|a| a.name == name)
+            .map(|a| a.value.as_str())
+    }
+}
+
+/// Render a decoded ABI value as text. Addresses and bytes are hex encoded and
+/// integers are written in decimal.
+pub(crate) fn format_token(token: &Token) -> String {
+    match token {
+        Token::Address(a) => format!("{:#x}", a),
+        Token::Uint(v) => v.to_string(),
+        Token::Int(v) => I256::from_raw(*v).to_string(),
+        Token::Bool(b) => b.to_string(),
+        Token::String(s) => s.clone(),
+        Token::Bytes(b) | Token::FixedBytes(b) => format!("0x{}", ethers::utils::hex::encode(b)),
+        Token::Array(items) | Token::FixedArray(items) | Token::Tuple(items) => {
+            let items: Vec<String> = items.iter().map(format_token).collect();
+            format!("[{}]", items.join(","))
+        }
+    }
+}
+
+/// Contract ABIs keyed by contract address
+#[derive(Debug, Default)]
+pub struct AbiRegistry(HashMap<Address, Abi>);
+
+impl AbiRegistry {
+    /// Load every `<address>.json` file in `dir`. A file may contain a bare ABI
+    /// array or a build artifact with the ABI under an `abi` key.
+    pub fn load_dir(dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut registry = Self::default();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let address = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(stem) => stem.parse::<Address>()?,
+                None => continue,
+            };
+            let value: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+            let abi = match value.get("abi") {
+                Some(inner) => serde_json::from_value(inner.clone())?,
+                None => serde_json::from_value(value)?,
+            };
+            registry.insert(address, abi);
+        }
+        Ok(registry)
+    }
+
+    pub fn insert(&mut self, address: Address, abi: Abi) {
+        self.0.insert(address, abi);
+    }
+
+    pub fn get(&self, address: &Address) -> Option<&Abi> {
+        self.0.get(address)
+    }
+
+    /// Decode `input` sent to contract `to` using its registered ABI.
+    pub fn decode_call(&self, to: &Address, input: &[u8]) -> Option<DecodedCall> {
+        if input.len() < 4 {
+            return None;
+        }
+        let function = self
+            .get(to)?
+            .functions()
+            .find(|f| f.short_signature() == input[..4])?;
+        let tokens = function.decode_input(&input[4..]).ok()?;
+        let args = function
+            .inputs
+            .iter()
+            .zip(tokens.iter())
+            .enumerate()
+            .map(|(i, (param, token))| DecodedArg {
+                name: if param.name.is_empty() {
+                    format!("arg{}", i)
+                } else {
+                    param.name.clone()
+                },
+                value: format_token(token),
+            })
+            .collect();
+        Some(DecodedCall {
+            name: function.name.clone(),
+            signature: function.signature(),
+            args,
+        })
+    }
+}
+
+/// Decode the calldata of every transaction sent to a contract in `registry`.
+pub fn decode_transactions(txs: &mut [Transaction], registry: &AbiRegistry) {
+    for tx in txs {
+        if let Some(to) = tx.to {
+            if let Some(call) = registry.decode_call(&to, &tx.input) {
+                tx.decoded = Some(call);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::abi::{self, parse_abi};
+    use ethers::types::U256;
+    use std::env;
+
+    fn transfer_input(to: Address, amount: u64) -> Vec<u8> {
+        let mut input = vec![0xa9, 0x05, 0x9c, 0xbb];
+        input.extend(abi::encode(&[
+            Token::Address(to),
+            Token::Uint(U256::from(amount)),
+        ]));
+        input
+    }
+
+    #[test]
+    fn decode_call_names_arguments() {
+        let contract = Address::repeat_byte(0x33);
+        let mut registry = AbiRegistry::default();
+        registry.insert(
+            contract,
+            parse_abi(&["function transfer(address to, uint256 amount)"]).unwrap(),
+        );
+        let mut txs = vec![
+            Transaction {
+                to: Some(contract),
+                input: transfer_input(Address::repeat_byte(0x22), 7).into(),
+                ..Default::default()
+            },
+            Transaction {
+                to: Some(Address::repeat_byte(0x44)),
+                input: transfer_input(Address::repeat_byte(0x22), 7).into(),
+                ..Default::default()
+            },
+        ];
+        decode_transactions(&mut txs, &registry);
+
+        let call = txs[0].decoded.as_ref().unwrap();
+        assert_eq!(call.name, "transfer");
+        assert_eq!(call.signature, "transfer(address,uint256)");
+        assert_eq!(
+            call.arg("to"),
+            Some("0x2222222222222222222222222222222222222222")
+        );
+        assert_eq!(call.arg("amount"), Some("7"));
+        assert!(txs[1].decoded.is_none());
+    }
+
+    #[test]
+    fn load_dir_reads_arrays_and_artifacts() {
+        let dir = env::temp_dir().join("abi_registry_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let abi = r#"[{"type":"function","name":"deposit","inputs":[],"outputs":[],"stateMutability":"payable"}]"#;
+        fs::write(
+            dir.join("0x1111111111111111111111111111111111111111.json"),
+            abi,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("0x2222222222222222222222222222222222222222.json"),
+            format!(r#"{{"abi":{}}}"#, abi),
+        )
+        .unwrap();
+
+        let registry = AbiRegistry::load_dir(&dir).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert!(registry.get(&Address::repeat_byte(0x11)).is_some());
+        assert!(registry.get(&Address::repeat_byte(0x22)).is_some());
+    }
+}
@@ -0,0 +1,224 @@
+use ethers::abi::{parse_abi, Event, RawLog};
+use ethers::types::{Address, Log};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+use crate::blockchain::{ReceiptSource, Transaction};
+use crate::decoder::{format_token, AbiRegistry, DecodedArg};
+
+/// Event signatures of common token, DEX, lending and perpetuals contracts used
+/// when a contract has no registered ABI
+static KNOWN_EVENTS: Lazy<Vec<Event>> = Lazy::new(|| {
+    let abi = parse_abi(&[
+        "event Transfer(address indexed from, address indexed to, uint256 value)",
+        "event Transfer(address indexed from, address indexed to, uint256 indexed tokenId)",
+        "event Approval(address indexed owner, address indexed spender, uint256 value)",
+        "event Deposit(address indexed dst, uint256 wad)",
+        "event Withdrawal(address indexed src, uint256 wad)",
+        "event Swap(address indexed sender, address indexed recipient, int256 amount0, int256 amount1, uint160 sqrtPriceX96, uint128 liquidity, int24 tick)",
+        "event Swap(address indexed sender, uint256 amount0In, uint256 amount1In, uint256 amount0Out, uint256 amount1Out, address indexed to)",
+        "event IncreaseLiquidity(uint256 indexed tokenId, uint128 liquidity, uint256 amount0, uint256 amount1)",
+        "event DecreaseLiquidity(uint256 indexed tokenId, uint128 liquidity, uint256 amount0, uint256 amount1)",
+        "event Collect(uint256 indexed tokenId, address recipient, uint256 amount0, uint256 amount1)",
+        "event Supply(address indexed reserve, address user, address indexed onBehalfOf, uint256 amount, uint16 indexed referralCode)",
+        "event Withdraw(address indexed reserve, address indexed user, address indexed to, uint256 amount)",
+        "event Borrow(address indexed reserve, address user, address indexed onBehalfOf, uint256 amount, uint8 interestRateMode, uint256 borrowRate, uint16 indexed referralCode)",
+        "event Repay(address indexed reserve, address indexed user, address indexed repayer, uint256 amount, bool useATokens)",
+        "event IncreasePosition(bytes32 key, address account, address collateralToken, address indexToken, uint256 collateralDelta, uint256 sizeDelta, bool isLong, uint256 price, uint256 fee)",
+        "event DecreasePosition(bytes32 key, address account, address collateralToken, address indexToken, uint256 collateralDelta, uint256 sizeDelta, bool isLong, uint256 price, uint256 fee)",
+        "event ClosePosition(bytes32 key, uint256 size, uint256 collateral, uint256 averagePrice, uint256 entryFundingRate, uint256 reserveAmount, int256 realisedPnl)",
+        "event LiquidatePosition(bytes32 key, address account, address collateralToken, address indexToken, bool isLong, uint256 size, uint256 collateral, uint256 reserveAmount, int256 realisedPnl, uint256 markPrice)",
+        "event UpdatePnl(bytes32 key, bool hasProfit, uint256 delta)",
+    ])
+    .expect("valid event signatures");
+    abi.events().cloned().collect()
+});
+
+/// An event log decoded from a transaction receipt
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DecodedEvent {
+    /// Contract that emitted the event
+    pub address: Address,
+    pub name: String,
+    pub args: Vec<DecodedArg>,
+}
+
+impl DecodedEvent {
+    /// Return the value of the argument called `name`.
+    pub fn arg(&self, name: &str) -> Option<&str> {
+        self.args
+            .iter()
+            .find(|a| a.name == name)
+            .map(|a| a.value.as_str())
+    }
+}
+
+fn parse_with<'a>(
+    events: impl Iterator<Item = &'a Event>,
+    log: &Log,
+) -> Option<(String, Vec<DecodedArg>)> {
+    let topic = *log.topics.first()?;
+    events
+        .filter(|e| !e.anonymous && e.signature() == topic)
+        .find_map(|e| {
+            let raw = RawLog {
+                topics: log.topics.clone(),
+                data: log.data.to_vec(),
+            };
+            let parsed = e.parse_log_whole(raw).ok()?;
+            let args = parsed
+                .params
+                .iter()
+                .map(|p| DecodedArg {
+                    name: p.name.clone(),
+                    value: format_token(&p.value),
+                })
+                .collect();
+            Some((e.name.clone(), args))
+        })
+}
+
+/// Decode `log` using the ABI registered for the emitting contract, falling back
+/// to the known event signatures.
+pub fn decode_log(log: &Log, registry: &AbiRegistry) -> Option<DecodedEvent> {
+    let (name, args) = registry
+        .get(&log.address)
+        .and_then(|abi| parse_with(abi.events(), log))
+        .or_else(|| parse_with(KNOWN_EVENTS.iter(), log))?;
+    Some(DecodedEvent {
+        address: log.address,
+        name,
+        args,
+    })
+}
+
+/// Fetch the receipt of every transaction and attach its decoded event logs.
+/// Logs matching neither a registered ABI nor a known signature are skipped.
+pub async fn fetch_events<R>(
+    source: &R,
+    txs: &mut [Transaction],
+    registry: &AbiRegistry,
+) -> Result<(), Box<dyn Error>>
+where
+    R: ReceiptSource + Sync,
+{
+    for tx in txs.iter_mut() {
+        if let Some(receipt) = source.get_receipt(tx.hash).await? {
+            tx.events = receipt
+                .logs
+                .iter()
+                .filter_map(|log| decode_log(log, registry))
+                .collect();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use ethers::abi::{self, Token};
+    use ethers::types::{TransactionReceipt, H256, U256};
+    use ethers::utils::keccak256;
+
+    fn topic(addr: Address) -> H256 {
+        H256::from(addr)
+    }
+
+    fn transfer_log(token: Address, from: Address, to: Address, value: u64) -> Log {
+        Log {
+            address: token,
+            topics: vec![
+                H256::from(keccak256("Transfer(address,address,uint256)")),
+                topic(from),
+                topic(to),
+            ],
+            data: abi::encode(&[Token::Uint(U256::from(value))]).into(),
+            ..Default::default()
+        }
+    }
+
+    struct MockReceipts;
+
+    #[async_trait]
+    impl ReceiptSource for MockReceipts {
+        async fn get_receipt(
+            &self,
+            hash: H256,
+        ) -> Result<Option<TransactionReceipt>, Box<dyn Error>> {
+            let log = transfer_log(
+                Address::repeat_byte(0x33),
+                Address::repeat_byte(0x11),
+                Address::repeat_byte(0x22),
+                5,
+            );
+            Ok(Some(TransactionReceipt {
+                transaction_hash: hash,
+                logs: vec![log],
+                ..Default::default()
+            }))
+        }
+    }
+
+    #[test]
+    fn decode_log_distinguishes_known_variants() {
+        let registry = AbiRegistry::default();
+        let erc20 = transfer_log(
+            Address::repeat_byte(0x33),
+            Address::repeat_byte(0x11),
+            Address::repeat_byte(0x22),
+            5,
+        );
+        let ev = decode_log(&erc20, &registry).unwrap();
+        assert_eq!(ev.name, "Transfer");
+        assert_eq!(ev.arg("value"), Some("5"));
+
+        let mut nft = erc20.clone();
+        nft.topics.push(H256::from_low_u64_be(9));
+        nft.data = Default::default();
+        let ev = decode_log(&nft, &registry).unwrap();
+        assert_eq!(ev.arg("tokenId"), Some("9"));
+
+        let unknown = Log {
+            topics: vec![H256::from(keccak256("Nope()"))],
+            ..Default::default()
+        };
+        assert!(decode_log(&unknown, &registry).is_none());
+    }
+
+    #[test]
+    fn registry_abi_takes_precedence() {
+        let contract = Address::repeat_byte(0x44);
+        let mut registry = AbiRegistry::default();
+        registry.insert(
+            contract,
+            parse_abi(&["event Ping(uint256 indexed round)"]).unwrap(),
+        );
+        let log = Log {
+            address: contract,
+            topics: vec![
+                H256::from(keccak256("Ping(uint256)")),
+                H256::from_low_u64_be(3),
+            ],
+            ..Default::default()
+        };
+        let ev = decode_log(&log, &registry).unwrap();
+        assert_eq!(ev.name, "Ping");
+        assert_eq!(ev.arg("round"), Some("3"));
+    }
+
+    #[tokio::test]
+    async fn fetch_events_attaches_logs() {
+        let mut txs = vec![Transaction::default()];
+        fetch_events(&MockReceipts, &mut txs, &AbiRegistry::default())
+            .await
+            .unwrap();
+        assert_eq!(txs[0].events.len(), 1);
+        assert_eq!(
+            txs[0].events[0].arg("to"),
+            Some("0x2222222222222222222222222222222222222222")
+        );
+    }
+}
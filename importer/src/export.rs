@@ -1,15 +1,19 @@
-use chrono::{NaiveDate, NaiveDateTime};
+use chrono::{DateTime, FixedOffset, NaiveDate};
+use chrono_tz::Tz;
 use csv::Writer;
 use ethers::types::Address;
 use ethers::utils::format_units;
+use serde::Serialize;
 use std::error::Error;
 use std::fs::File;
 use std::path::Path;
 
-use crate::{blockchain, tokens};
+use crate::blockchain;
+use crate::protocols;
+use crate::tokens::{self, MergeGroups, TokenKind};
 
 /// A single split in a transaction for GnuCash CSV exports
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Split {
     pub id: String,
     pub date: NaiveDate,
@@ -17,22 +21,97 @@ pub struct Split {
     pub account: String,
     pub commodity: String,
     pub amount: f64,
+    /// Free-form note on the split, such as the ID of an NFT
+    pub memo: String,
+    /// Local time the transaction was mined, or the end of the period for
+    /// accrued interest
+    pub time: Option<DateTime<FixedOffset>>,
 }
 
-fn value_to_f64(value: ethers::types::U256, decimals: u32) -> f64 {
+/// Convert a block timestamp into the local time of `tz`.
+pub fn local_time(timestamp: u64, tz: Tz) -> DateTime<FixedOffset> {
+    DateTime::from_timestamp(timestamp as i64, 0)
+        .unwrap_or_default()
+        .with_timezone(&tz)
+        .fixed_offset()
+}
+
+pub(crate) fn value_to_f64(value: ethers::types::U256, decimals: u32) -> f64 {
     format_units(value, decimals)
         .unwrap_or_else(|_| "0".to_string())
         .parse::<f64>()
         .unwrap_or(0.0)
 }
 
-/// Convert blockchain transactions into GnuCash CSV transactions
+/// Return the GnuCash account tracking the debt represented by a liability token.
+pub fn liability_account(symbol: &str) -> String {
+    format!("Liabilities:{}", symbol)
+}
+
+/// Return the split amount for a change of `held` liability tokens in the
+/// wallet. Debt counts against the wallet, so borrowing and accrued interest
+/// are negative and repayment positive.
+pub fn liability_amount(held: f64) -> f64 {
+    -held
+}
+
+/// Return the splits of the NFTs sent or received by `address` in `tx`. Every
+/// collection is its own commodity with the token ID in the memo.
+fn nft_splits(
+    address: Address,
+    tx: &blockchain::Transaction,
+    time: DateTime<FixedOffset>,
+    description: &str,
+    account: &str,
+) -> Vec<Split> {
+    let mut res = Vec::new();
+    for nft in &tx.nft_transfers {
+        let amount = value_to_f64(nft.amount, 0);
+        let amount = if nft.from == address {
+            -amount
+        } else if nft.to == Some(address) {
+            amount
+        } else {
+            continue;
+        };
+        let commodity = nft.commodity();
+        res.push(Split {
+            id: format!("{:#x}", tx.hash),
+            date: time.date_naive(),
+            description: description.to_string(),
+            account: tx
+                .split_accounts
+                .get(&commodity)
+                .cloned()
+                .unwrap_or_else(|| account.to_string()),
+            commodity,
+            amount,
+            memo: format!("#{}", nft.token_id),
+            time: Some(time),
+        });
+    }
+    res
+}
+
+/// Convert blockchain transactions into GnuCash CSV transactions. Splits carry the
+/// change of the wallet against the transaction's category account, except for
+/// liability tokens which are booked directly on their `Liabilities:` account
+/// with borrowing as a negative (credit) amount and repayment as a positive one.
+///
+/// Transactions recognized by one of the built-in [`protocols::ProtocolAdapter`]s are booked
+/// as the adapter structures them, unless an account was assigned explicitly.
+/// NFTs are booked against the transaction's account in either case.
 pub fn from_chain(address: Address, txs: &[blockchain::Transaction]) -> Vec<Split> {
+    from_chain_in(address, txs, Tz::UTC)
+}
+
+/// Like [`from_chain`], dating every split by the local time of `tz`.
+pub fn from_chain_in(address: Address, txs: &[blockchain::Transaction], tz: Tz) -> Vec<Split> {
+    let adapters = protocols::builtin_adapters();
     let mut res = Vec::new();
     for tx in txs {
-        let dt = NaiveDateTime::from_timestamp_opt(tx.timestamp as i64, 0)
-            .unwrap_or_else(|| NaiveDateTime::from_timestamp(tx.timestamp as i64, 0));
-        let date = dt.date();
+        let time = local_time(tx.timestamp, tz);
+        let date = time.date_naive();
         let eth_amount = value_to_f64(tx.value, 18);
 
         let default_desc = if tx.to == Some(address) {
@@ -40,13 +119,53 @@ pub fn from_chain(address: Address, txs: &[blockchain::Transaction]) -> Vec<Spli
         } else {
             "withdrawal".to_string()
         };
-        let description = tx
-            .description
+        let with_counterparty = |desc: String| match tx.counterparty.as_deref() {
+            Some(cp) => format!("{} - {}", desc, cp),
+            None => desc,
+        };
+
+        let account = tx
+            .account
             .clone()
             .or_else(|| tx.category.clone())
-            .unwrap_or_else(|| default_desc.clone());
-        let account = tx.category.clone().unwrap_or_else(|| "Unknown".to_string());
+            .unwrap_or_else(|| "Unknown".to_string());
 
+        let structured = match tx.account {
+            Some(_) => None,
+            None => adapters.iter().find_map(|a| a.structure(address, tx)),
+        };
+        if let Some(structured) = structured {
+            let description =
+                with_counterparty(tx.description.clone().unwrap_or(structured.description));
+            for leg in structured.legs {
+                res.push(Split {
+                    id: format!("{:#x}", tx.hash),
+                    date,
+                    description: description.clone(),
+                    account: tx
+                        .split_accounts
+                        .get(&leg.commodity)
+                        .cloned()
+                        .unwrap_or(leg.account),
+                    commodity: leg.commodity,
+                    amount: leg.amount,
+                    memo: String::new(),
+                    time: Some(time),
+                });
+            }
+            let nft_account = structured.nft_account.unwrap_or_else(|| account.clone());
+            res.extend(nft_splits(address, tx, time, &description, &nft_account));
+            continue;
+        }
+
+        let description = with_counterparty(
+            tx.description
+                .clone()
+                .or_else(|| tx.category.clone())
+                .or_else(|| tx.decoded.as_ref().map(|c| c.name.clone()))
+                .or_else(|| tx.guessed_method.clone())
+                .unwrap_or_else(|| default_desc.clone()),
+        );
         if eth_amount != 0.0 {
             let mut amount = eth_amount;
             if tx.from == address {
@@ -56,35 +175,62 @@ pub fn from_chain(address: Address, txs: &[blockchain::Transaction]) -> Vec<Spli
                 id: format!("{:#x}", tx.hash),
                 date,
                 description: description.clone(),
-                account: account.clone(),
+                account: tx
+                    .split_accounts
+                    .get("ETH")
+                    .cloned()
+                    .unwrap_or_else(|| account.clone()),
                 commodity: "ETH".to_string(),
                 amount,
+                memo: String::new(),
+                time: Some(time),
             });
         }
 
         for tr in &tx.transfers {
-            if let Some(sym) = tokens::get_symbol(&tr.token_contract) {
+            if let Some(info) = tokens::GOOD_TOKENS.get(&tr.token_contract) {
                 let decimals = tr.token_decimal.parse::<u32>().unwrap_or(18);
                 let mut amount = value_to_f64(tr.value, decimals);
                 if tr.from == address {
                     amount = -amount;
                 }
+                if info.kind == TokenKind::Liability {
+                    amount = liability_amount(amount);
+                }
+                let account = match tx.split_accounts.get(info.symbol) {
+                    Some(acc) => acc.clone(),
+                    None if info.kind == TokenKind::Liability => liability_account(info.symbol),
+                    None => account.clone(),
+                };
                 res.push(Split {
                     id: format!("{:#x}", tx.hash),
                     date,
                     description: description.clone(),
-                    account: account.clone(),
-                    commodity: sym.to_string(),
+                    account,
+                    commodity: info.symbol.to_string(),
                     amount,
+                    memo: String::new(),
+                    time: Some(time),
                 });
             }
         }
+        res.extend(nft_splits(address, tx, time, &description, &account));
     }
     res
 }
 
-/// Write the provided transactions to `path` in CSV format compatible with GnuCash
-pub fn write_csv(path: &Path, txs: &[Split]) -> Result<(), Box<dyn Error>> {
+/// Book every commodity belonging to one of `groups` as the group's commodity,
+/// e.g. bridged USDC.e as USDC.
+pub fn merge_commodities(splits: &mut [Split], groups: &MergeGroups) {
+    for split in splits {
+        split.commodity = groups.commodity(&split.commodity).to_string();
+    }
+}
+
+/// Write the provided transactions to `path` in CSV format compatible with GnuCash.
+/// With `timestamps` the date column holds the full RFC 3339 time of splits that
+/// have one.
+pub fn write_csv(path: &Path, txs: &[Split], timestamps: bool) -> Result<(), Box<dyn Error>> {
     let file = File::create(path)?;
     let mut wtr = Writer::from_writer(file);
     wtr.write_record([
@@ -94,21 +240,32 @@ pub fn write_csv(path: &Path, txs: &[Split]) -> Result<(), Box<dyn Error>> {
         "Account",
         "Commodity",
         "Amount",
+        "Memo",
     ])?;
     for tx in txs {
         wtr.write_record([
             tx.id.clone(),
-            tx.date.to_string(),
+            match tx.time {
+                Some(time) if timestamps => time.to_rfc3339(),
+                _ => tx.date.to_string(),
+            },
             tx.description.clone(),
             tx.account.clone(),
             tx.commodity.clone(),
             tx.amount.to_string(),
+            tx.memo.clone(),
         ])?;
     }
     wtr.flush()?;
     Ok(())
 }
 
+/// Write the provided transactions to `path` as a JSON array of splits
+pub fn write_json(path: &Path, txs: &[Split]) -> Result<(), Box<dyn Error>> {
+    serde_json::to_writer_pretty(File::create(path)?, txs)?;
+    Ok(())
+}
+
 /// Write all ERC-20 token transfers in `txs` to `path` as CSV for debugging
 pub fn write_transfers_csv(
     path: &Path,
@@ -116,7 +273,14 @@ pub fn write_transfers_csv(
 ) -> Result<(), Box<dyn Error>> {
     let file = File::create(path)?;
     let mut wtr = Writer::from_writer(file);
-    wtr.write_record(["Transaction ID", "Token", "From", "To", "Amount"])?;
+    wtr.write_record([
+        "Transaction ID",
+        "Token",
+        "From",
+        "To",
+        "Amount",
+        "Counterparty",
+    ])?;
     for tx in txs {
         for tr in &tx.transfers {
             let symbol = tokens::get_symbol(&tr.token_contract).unwrap_or(tr.token_symbol.as_str());
@@ -128,6 +292,7 @@ pub fn write_transfers_csv(
                 format!("{:#x}", tr.from),
                 tr.to.map(|a| format!("{:#x}", a)).unwrap_or_default(),
                 amount,
+                tr.counterparty.clone().unwrap_or_default(),
             ])?;
         }
     }
@@ -138,7 +303,7 @@ pub fn write_transfers_csv(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::blockchain::{Erc20Transfer, Transaction as ChainTx};
+    use crate::blockchain::{Erc20Transfer, NftStandard, NftTransfer, Transaction as ChainTx};
     use ethers::types::{H256, U256};
     use std::env;
     use std::fs;
@@ -155,6 +320,7 @@ mod tests {
             token_name: "TEST".to_string(),
             token_symbol: "TST".to_string(),
             token_decimal: "18".to_string(),
+            counterparty: None,
         };
 
         let chain_tx = ChainTx {
@@ -167,6 +333,7 @@ mod tests {
             category: Some("Trade".to_string()),
             description: None,
             transfers: vec![transfer],
+            ..Default::default()
         };
         let res = from_chain(Address::repeat_byte(0x11), &[chain_tx]);
         assert_eq!(res.len(), 2);
@@ -175,11 +342,153 @@ mod tests {
         assert_eq!(res[1].id, expected_id);
         assert_eq!(res[0].commodity, "ETH");
         assert!(res[0].amount < 0.0);
-        assert_eq!(res[1].commodity, "USDC");
+        assert_eq!(res[1].commodity, "USDC.e");
         assert!(res[1].amount < 0.0);
         assert_eq!(res[0].account, "Trade");
     }
 
+    #[test]
+    fn merge_commodities_uses_groups() {
+        let split = |commodity: &str| Split {
+            id: String::new(),
+            date: NaiveDate::default(),
+            description: String::new(),
+            account: "Trade".to_string(),
+            commodity: commodity.to_string(),
+            amount: 1.0,
+            memo: String::new(),
+            time: None,
+        };
+        let mut splits = vec![
+            split("USDC.e"),
+            split("aUSDC"),
+            split("Debt USDC"),
+            split("ETH"),
+        ];
+        merge_commodities(&mut splits, &MergeGroups::builtin());
+        let commodities: Vec<&str> = splits.iter().map(|s| s.commodity.as_str()).collect();
+        assert_eq!(commodities, ["USDC", "aUSDC", "Debt USDC", "ETH"]);
+    }
+
+    #[test]
+    fn counterparty_appended_to_description() {
+        let chain_tx = ChainTx {
+            from: Address::repeat_byte(0x11),
+            value: U256::from(10u64.pow(18)),
+            category: Some("Rent".to_string()),
+            counterparty: Some("Alice".to_string()),
+            ..Default::default()
+        };
+        let res = from_chain(Address::repeat_byte(0x11), &[chain_tx]);
+        assert_eq!(res[0].description, "Rent - Alice");
+    }
+
+    #[test]
+    fn dates_follow_timezone() {
+        let chain_tx = ChainTx {
+            from: Address::repeat_byte(0x11),
+            value: U256::from(10u64.pow(18)),
+            // 2024-01-01 02:00 UTC
+            timestamp: 1_704_074_400,
+            ..Default::default()
+        };
+        let res = from_chain_in(
+            Address::repeat_byte(0x11),
+            &[chain_tx],
+            Tz::America__New_York,
+        );
+        assert_eq!(res[0].date, NaiveDate::from_ymd_opt(2023, 12, 31).unwrap());
+
+        let path = env::temp_dir().join("timezone_test.csv");
+        write_csv(&path, &res, true).unwrap();
+        let csv = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(path);
+        assert!(csv.contains(",2023-12-31T21:00:00-05:00,"));
+    }
+
+    #[test]
+    fn liability_tokens_booked_as_liabilities() {
+        let borrow = Erc20Transfer {
+            token_contract: Address::from_str("0xf611aeb5013fd2c0511c9cd55c7dc5c1140741a6")
+                .unwrap(),
+            from: Address::zero(),
+            to: Some(Address::repeat_byte(0x11)),
+            value: U256::from(10u64.pow(6)),
+            token_name: "Debt".to_string(),
+            token_symbol: "variableDebtArbUSDCn".to_string(),
+            token_decimal: "6".to_string(),
+            counterparty: None,
+        };
+        let chain_tx = ChainTx {
+            hash: H256::zero(),
+            block_number: 1,
+            timestamp: 0,
+            from: Address::repeat_byte(0x11),
+            to: Some(Address::repeat_byte(0x22)),
+            value: U256::zero(),
+            category: Some("Borrow".to_string()),
+            description: None,
+            transfers: vec![borrow],
+            ..Default::default()
+        };
+        let res = from_chain(Address::repeat_byte(0x11), &[chain_tx]);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].account, "Liabilities:Debt USDC");
+        assert_eq!(res[0].amount, -1.0);
+    }
+
+    #[test]
+    fn protocol_adapters_structure_transactions() {
+        let me = Address::repeat_byte(0x11);
+        let tx = ChainTx {
+            from: me,
+            value: U256::exp10(18),
+            category: Some("Trade".to_string()),
+            guessed_method: Some("createIncreasePositionETH".to_string()),
+            ..Default::default()
+        };
+        let res = from_chain(me, std::slice::from_ref(&tx));
+        assert_eq!(res[0].account, "Assets:GMX:Margin");
+        assert_eq!(res[0].description, "GMX open position");
+
+        let explicit = ChainTx {
+            account: Some("Expenses:Trading".to_string()),
+            ..tx
+        };
+        let res = from_chain(me, &[explicit]);
+        assert_eq!(res[0].account, "Expenses:Trading");
+    }
+
+    #[test]
+    fn nfts_booked_per_collection() {
+        let me = Address::repeat_byte(0x11);
+        let nft = |from: Address, to: Address, id: &str| NftTransfer {
+            standard: NftStandard::Erc721,
+            contract: Address::repeat_byte(0x77),
+            from,
+            to: Some(to),
+            token_id: id.to_string(),
+            amount: U256::one(),
+            collection_name: "Positions".to_string(),
+            collection_symbol: "UNI-V3-POS".to_string(),
+        };
+        let tx = ChainTx {
+            from: me,
+            category: Some("Assets:NFTs".to_string()),
+            nft_transfers: vec![
+                nft(Address::zero(), me, "7"),
+                nft(Address::zero(), Address::repeat_byte(0x22), "8"),
+            ],
+            ..Default::default()
+        };
+        let res = from_chain(me, &[tx]);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].commodity, "NFT UNI-V3-POS");
+        assert_eq!(res[0].account, "Assets:NFTs");
+        assert_eq!(res[0].amount, 1.0);
+        assert_eq!(res[0].memo, "#7");
+    }
+
     #[test]
     fn write_transfers_csv_creates_file() {
         let transfer = Erc20Transfer {
@@ -191,6 +500,7 @@ mod tests {
             token_name: "TEST".to_string(),
             token_symbol: "TST".to_string(),
             token_decimal: "18".to_string(),
+            counterparty: None,
         };
 
         let chain_tx = ChainTx {
@@ -203,6 +513,7 @@ mod tests {
             category: None,
             description: None,
             transfers: vec![transfer],
+            ..Default::default()
         };
 
         let path = env::temp_dir().join("transfers_test.csv");
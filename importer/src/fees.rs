@@ -0,0 +1,216 @@
+use chrono::{Datelike, NaiveDate};
+use chrono_tz::Tz;
+use csv::Writer;
+use ethers::types::{Address, U256};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io;
+
+use crate::blockchain::{ReceiptSource, Transaction};
+use crate::export::{local_time, value_to_f64, Split};
+
+/// Transaction fee paid in wei, split into L2 execution and the L1 calldata
+/// component
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Fee {
+    pub l2: U256,
+    pub l1: U256,
+}
+
+/// Expense accounts the two fee components are booked to
+#[derive(Clone, Debug)]
+pub struct FeeAccounts {
+    pub l2: String,
+    pub l1: String,
+}
+
+impl Default for FeeAccounts {
+    fn default() -> Self {
+        Self {
+            l2: "Expenses:Fees:L2 Execution".to_string(),
+            l1: "Expenses:Fees:L1 Data".to_string(),
+        }
+    }
+}
+
+/// Fetch the receipt of every transaction sent by `address` and attach the fee
+/// it paid. The L1 part is the `gasUsedForL1` reported by Arbitrum nodes priced
+/// at the effective gas price.
+pub async fn fetch_fees<R>(
+    source: &R,
+    address: Address,
+    txs: &mut [Transaction],
+) -> Result<(), Box<dyn Error>>
+where
+    R: ReceiptSource + Sync,
+{
+    for tx in txs
+        .iter_mut()
+        .filter(|tx| tx.from == address && !tx.transfer_only)
+    {
+        let Some(receipt) = source.get_receipt(tx.hash).await? else {
+            continue;
+        };
+        let (Some(gas_used), Some(price)) = (receipt.gas_used, receipt.effective_gas_price) else {
+            continue;
+        };
+        let l1_gas = receipt
+            .other
+            .get_deserialized::<U256>("gasUsedForL1")
+            .transpose()?
+            .unwrap_or_default()
+            .min(gas_used);
+        tx.fee = Some(Fee {
+            l2: (gas_used - l1_gas) * price,
+            l1: l1_gas * price,
+        });
+    }
+    Ok(())
+}
+
+/// Book the fees attached to `txs` as ETH spent on the accounts in `accounts`,
+/// dated in `tz`.
+pub fn fee_splits(txs: &[Transaction], accounts: &FeeAccounts, tz: Tz) -> Vec<Split> {
+    let mut res = Vec::new();
+    for tx in txs {
+        let Some(fee) = tx.fee else {
+            continue;
+        };
+        let time = local_time(tx.timestamp, tz);
+        for (account, wei) in [(&accounts.l2, fee.l2), (&accounts.l1, fee.l1)] {
+            if wei.is_zero() {
+                continue;
+            }
+            res.push(Split {
+                id: format!("{:#x}", tx.hash),
+                date: time.date_naive(),
+                description: "Network fee".to_string(),
+                account: account.clone(),
+                commodity: "ETH".to_string(),
+                amount: -value_to_f64(wei, 18),
+                memo: String::new(),
+                time: Some(time),
+            });
+        }
+    }
+    res
+}
+
+/// Fees paid in a calendar month, in ETH
+#[derive(Debug, Default, PartialEq)]
+pub struct FeeSummary {
+    /// First day of the month
+    pub month: NaiveDate,
+    pub transactions: usize,
+    pub l2: f64,
+    pub l1: f64,
+}
+
+/// Sum the fees attached to `txs` per calendar month in `tz`.
+pub fn monthly_summary(txs: &[Transaction], tz: Tz) -> Vec<FeeSummary> {
+    let mut months: BTreeMap<NaiveDate, FeeSummary> = BTreeMap::new();
+    for tx in txs {
+        let Some(fee) = tx.fee else {
+            continue;
+        };
+        let month = local_time(tx.timestamp, tz)
+            .date_naive()
+            .with_day(1)
+            .unwrap_or_default();
+        let entry = months.entry(month).or_insert_with(|| FeeSummary {
+            month,
+            ..Default::default()
+        });
+        entry.transactions += 1;
+        entry.l2 += value_to_f64(fee.l2, 18);
+        entry.l1 += value_to_f64(fee.l1, 18);
+    }
+    months.into_values().collect()
+}
+
+/// Write a monthly fee summary as CSV to `writer`
+pub fn write_fee_summary<W: io::Write>(
+    writer: W,
+    summary: &[FeeSummary],
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = Writer::from_writer(writer);
+    wtr.write_record(["Month", "Transactions", "L2 Execution", "L1 Data", "Total"])?;
+    for s in summary {
+        wtr.write_record([
+            s.month.format("%Y-%m").to_string(),
+            s.transactions.to_string(),
+            s.l2.to_string(),
+            s.l1.to_string(),
+            (s.l2 + s.l1).to_string(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use ethers::types::{TransactionReceipt, H256};
+
+    struct MockReceipts;
+
+    #[async_trait]
+    impl ReceiptSource for MockReceipts {
+        async fn get_receipt(
+            &self,
+            hash: H256,
+        ) -> Result<Option<TransactionReceipt>, Box<dyn Error>> {
+            let mut receipt = TransactionReceipt {
+                transaction_hash: hash,
+                gas_used: Some(U256::from(100)),
+                effective_gas_price: Some(U256::from(10)),
+                ..Default::default()
+            };
+            receipt
+                .other
+                .insert("gasUsedForL1".to_string(), "0x1e".into());
+            Ok(Some(receipt))
+        }
+    }
+
+    #[tokio::test]
+    async fn fees_split_into_l1_and_l2() {
+        let me = Address::repeat_byte(0x11);
+        let mut txs = vec![
+            Transaction {
+                from: me,
+                timestamp: 1_700_000_000,
+                ..Default::default()
+            },
+            Transaction {
+                from: Address::repeat_byte(0x22),
+                ..Default::default()
+            },
+        ];
+        fetch_fees(&MockReceipts, me, &mut txs).await.unwrap();
+        assert_eq!(
+            txs[0].fee,
+            Some(Fee {
+                l2: U256::from(700),
+                l1: U256::from(300),
+            })
+        );
+        assert_eq!(txs[1].fee, None);
+
+        let splits = fee_splits(&txs, &FeeAccounts::default(), Tz::UTC);
+        assert_eq!(splits.len(), 2);
+        assert_eq!(splits[1].account, "Expenses:Fees:L1 Data");
+        assert_eq!(splits[1].amount, -3e-16);
+
+        let summary = monthly_summary(&txs, Tz::UTC);
+        assert_eq!(summary.len(), 1);
+        assert_eq!(
+            summary[0].month,
+            NaiveDate::from_ymd_opt(2023, 11, 1).unwrap()
+        );
+        assert_eq!(summary[0].transactions, 1);
+    }
+}
@@ -0,0 +1,329 @@
+use chrono::{Datelike, Days, Months, NaiveDate};
+use chrono_tz::Tz;
+use ethers::types::{Address, I256};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::str::FromStr;
+
+use crate::blockchain::{self, ChainState, TxSource};
+use crate::export::{self, local_time, value_to_f64, Split};
+use crate::tokens::{self, TokenKind};
+
+/// Length of the periods interest is accrued over
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Granularity {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl FromStr for Granularity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(Self::Daily),
+            "weekly" => Ok(Self::Weekly),
+            "monthly" => Ok(Self::Monthly),
+            other => Err(format!("unknown granularity: {}", other)),
+        }
+    }
+}
+
+impl Granularity {
+    /// Return the last day of the period containing `date`.
+    fn period_end(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Self::Daily => date,
+            Self::Weekly => {
+                let days = 6 - date.weekday().num_days_from_monday();
+                date + Days::new(days as u64)
+            }
+            Self::Monthly => {
+                let first = date.with_day(1).unwrap_or(date);
+                first + Months::new(1) - Days::new(1)
+            }
+        }
+    }
+}
+
+/// Net transfers of a single rebasing token by `address` in the token's
+/// smallest unit, keyed by date
+#[derive(Debug, Default)]
+struct TokenFlows {
+    decimals: u32,
+    by_date: BTreeMap<NaiveDate, I256>,
+}
+
+fn signed_to_f64(value: I256, decimals: u32) -> f64 {
+    let amount = value_to_f64(value.unsigned_abs(), decimals);
+    if value.is_negative() {
+        -amount
+    } else {
+        amount
+    }
+}
+
+fn token_flows(
+    address: Address,
+    txs: &[blockchain::Transaction],
+    tz: Tz,
+) -> BTreeMap<Address, TokenFlows> {
+    let mut map: BTreeMap<Address, TokenFlows> = BTreeMap::new();
+    for tx in txs {
+        for tr in tx
+            .transfers
+            .iter()
+            .filter(|tr| tokens::is_rebasing(&tr.token_contract))
+        {
+            let flows = map.entry(tr.token_contract).or_default();
+            flows.decimals = tr.token_decimal.parse::<u32>().unwrap_or(18);
+            let mut amount = I256::from_raw(tr.value);
+            if tr.from == address {
+                amount = -amount;
+            } else if tr.to != Some(address) {
+                continue;
+            }
+            *flows
+                .by_date
+                .entry(local_time(tx.timestamp, tz).date_naive())
+                .or_default() += amount;
+        }
+    }
+    map
+}
+
+/// Derive interest accrued on rebasing tokens held by `address` from balance
+/// snapshots taken at the end of every period up to `until`, starting from the
+/// balance held before the first transfer in `txs`. The growth of a balance not
+/// explained by transfers is booked as interest income for deposit tokens and
+/// as interest expense for debt tokens, signed like every other liability split.
+pub async fn accrue<C, S>(
+    client: &C,
+    state: &S,
+    address: Address,
+    txs: &[blockchain::Transaction],
+    granularity: Granularity,
+    until: NaiveDate,
+) -> Result<Vec<Split>, Box<dyn Error>>
+where
+    C: TxSource + Sync,
+    S: ChainState + Sync,
+{
+    accrue_in(client, state, address, txs, granularity, until, Tz::UTC).await
+}
+
+/// Like [`accrue`], with periods ending at midnight in `tz`.
+pub async fn accrue_in<C, S>(
+    client: &C,
+    state: &S,
+    address: Address,
+    txs: &[blockchain::Transaction],
+    granularity: Granularity,
+    until: NaiveDate,
+    tz: Tz,
+) -> Result<Vec<Split>, Box<dyn Error>>
+where
+    C: TxSource + Sync,
+    S: ChainState + Sync,
+{
+    let mut res = Vec::new();
+    for (token, flows) in token_flows(address, txs, tz) {
+        let info = match tokens::GOOD_TOKENS.get(&token) {
+            Some(info) => info,
+            None => continue,
+        };
+        let symbol = info.symbol;
+        let mut start = match flows.by_date.keys().next() {
+            Some(date) => *date,
+            None => continue,
+        };
+
+        // the balance at the end of the day before the first transfer, which is
+        // not zero if the wallet held the token before the transactions given
+        let opening = blockchain::block_at_end_of_in(client, start - Days::new(1), tz).await?;
+        let mut previous =
+            I256::from_raw(blockchain::token_balance(state, token, address, Some(opening)).await?);
+        while start <= until {
+            let end = granularity.period_end(start).min(until);
+            let block = blockchain::block_at_end_of_in(client, end, tz).await?;
+            let raw = blockchain::token_balance(state, token, address, Some(block)).await?;
+            let snapshot = I256::from_raw(raw);
+            let transferred = flows
+                .by_date
+                .range(start..=end)
+                .fold(I256::zero(), |sum, (_, a)| sum + *a);
+            let interest = snapshot - previous - transferred;
+            if !interest.is_zero() {
+                let interest = signed_to_f64(interest, flows.decimals);
+                let (account, amount) = if info.kind == TokenKind::Liability {
+                    ("Expenses:Interest", export::liability_amount(interest))
+                } else {
+                    ("Income:Interest", interest)
+                };
+                res.push(Split {
+                    id: format!("interest-{:#x}-{}", token, end),
+                    date: end,
+                    description: format!("{} interest", symbol),
+                    account: account.to_string(),
+                    commodity: symbol.to_string(),
+                    amount,
+                    memo: String::new(),
+                    time: Some(local_time(blockchain::end_of_day(end, tz), tz)),
+                });
+            }
+            previous = snapshot;
+            start = end + Days::new(1);
+            // a closed position accrues nothing until it is opened again
+            if snapshot.is_zero() {
+                match flows.by_date.range(start..).next() {
+                    Some((date, _)) => start = *date,
+                    None => break,
+                }
+            }
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::test_util::{MockClient, MockState};
+    use crate::blockchain::{Erc20Transfer, Transaction};
+    use ethers::types::U256;
+
+    /// Blocks are numbered by day since the epoch
+    fn client() -> MockClient {
+        MockClient::with_block_time(86_400)
+    }
+
+    /// Balance grows by one unit every day after a deposit of 100 on day 1 and
+    /// is withdrawn in full on day `closed_on`
+    fn growing(closed_on: u64) -> MockState {
+        MockState::new(move |_, block| {
+            let day = block.unwrap_or_default();
+            U256::from(if (1..closed_on).contains(&day) {
+                99 + day
+            } else {
+                0
+            })
+        })
+    }
+
+    fn transfer(day: u64, from: Address, to: Address, value: u64) -> Transaction {
+        Transaction {
+            timestamp: day * 86_400,
+            transfers: vec![Erc20Transfer {
+                token_contract: "0xf611aeb5013fd2c0511c9cd55c7dc5c1140741a6"
+                    .parse()
+                    .unwrap(),
+                from,
+                to: Some(to),
+                value: U256::from(value),
+                token_name: "Debt".to_string(),
+                token_symbol: "Debt".to_string(),
+                token_decimal: "0".to_string(),
+                counterparty: None,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn period_end_by_granularity() {
+        let date = NaiveDate::from_ymd_opt(2024, 2, 14).unwrap();
+        assert_eq!(Granularity::Daily.period_end(date), date);
+        assert_eq!(
+            Granularity::Weekly.period_end(date),
+            NaiveDate::from_ymd_opt(2024, 2, 18).unwrap()
+        );
+        assert_eq!(
+            Granularity::Monthly.period_end(date),
+            NaiveDate::from_ymd_opt(2024, 2, 29).unwrap()
+        );
+        assert_eq!("weekly".parse::<Granularity>(), Ok(Granularity::Weekly));
+    }
+
+    #[tokio::test]
+    async fn accrue_books_growth_as_interest() {
+        let address = Address::repeat_byte(0x11);
+        let tx = transfer(1, Address::zero(), address, 100);
+        let until = NaiveDate::from_ymd_opt(1970, 1, 4).unwrap();
+        let res = accrue(
+            &client(),
+            &growing(u64::MAX),
+            address,
+            &[tx],
+            Granularity::Daily,
+            until,
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].account, "Expenses:Interest");
+        assert_eq!(res[0].commodity, "Debt USDC");
+        assert_eq!(res[0].amount, -1.0);
+        assert_eq!(res[1].date, until);
+    }
+
+    #[tokio::test]
+    async fn accrue_starts_from_opening_balance() {
+        let address = Address::repeat_byte(0x11);
+        // 50 were held before the deposit of 100 on day 1
+        let state = MockState::new(|_, block| {
+            let day = block.unwrap_or_default();
+            U256::from(if day == 0 { 50 } else { 150 + day })
+        });
+        let tx = transfer(1, Address::zero(), address, 100);
+        let until = NaiveDate::from_ymd_opt(1970, 1, 2).unwrap();
+        let res = accrue(&client(), &state, address, &[tx], Granularity::Daily, until)
+            .await
+            .unwrap();
+        let amounts: Vec<f64> = res.iter().map(|s| s.amount).collect();
+        assert_eq!(amounts, [-1.0]);
+    }
+
+    #[tokio::test]
+    async fn accrue_stops_after_position_is_closed() {
+        let address = Address::repeat_byte(0x11);
+        let txs = [
+            transfer(1, Address::zero(), address, 100),
+            transfer(3, address, Address::zero(), 102),
+        ];
+        let state = growing(3);
+        let until = NaiveDate::from_ymd_opt(1970, 1, 12).unwrap();
+        let res = accrue(&client(), &state, address, &txs, Granularity::Daily, until)
+            .await
+            .unwrap();
+        let amounts: Vec<f64> = res.iter().map(|s| s.amount).collect();
+        assert_eq!(amounts, [-1.0, -1.0]);
+        // the opening balance and the three days the position was open
+        assert_eq!(state.calls(), 4);
+    }
+
+    #[tokio::test]
+    async fn accrue_dates_periods_in_timezone() {
+        let address = Address::repeat_byte(0x11);
+        let tx = transfer(1, Address::zero(), address, 100);
+        let tz: Tz = "America/New_York".parse().unwrap();
+        let until = NaiveDate::from_ymd_opt(1970, 1, 2).unwrap();
+        let res = accrue_in(
+            &client(),
+            &growing(u64::MAX),
+            address,
+            &[tx],
+            Granularity::Daily,
+            until,
+            tz,
+        )
+        .await
+        .unwrap();
+        // the deposit at midnight UTC falls on the evening before in New York,
+        // so the next day is a full period of interest
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].date, until);
+        let time = res[0].time.unwrap();
+        assert_eq!(time.to_rfc3339(), "1970-01-02T23:59:59-05:00");
+    }
+}
@@ -1,3 +1,19 @@
 pub mod blockchain;
+pub mod bridge;
+pub mod decoder;
+pub mod events;
 pub mod export;
+pub mod fees;
+pub mod interest;
+pub mod metadata;
+pub mod overrides;
+pub mod prices;
+pub mod protocols;
+pub mod reconcile;
+pub mod report;
+pub mod rules;
+pub mod selectors;
+pub mod spam;
+pub mod store;
+pub mod tags;
 pub mod tokens;
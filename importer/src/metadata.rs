@@ -0,0 +1,242 @@
+use ethers::abi::{self, ParamType, Token};
+use ethers::types::Address;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::blockchain::{self, ChainState, Transaction};
+
+/// ERC-20 metadata read from the token contract
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TokenMetadata {
+    pub decimals: u32,
+    pub symbol: String,
+    pub name: String,
+}
+
+/// Query a string-returning view function. Tokens predating the ERC-20 standard
+/// return `bytes32`, which is decoded as a null-padded string.
+async fn call_string<S>(
+    state: &S,
+    token: Address,
+    selector: [u8; 4],
+) -> Result<String, Box<dyn Error>>
+where
+    S: ChainState + Sync,
+{
+    let out = state.call(token, selector.to_vec().into(), None).await?;
+    if out.len() == 32 {
+        let end = out.iter().position(|b| *b == 0).unwrap_or(32);
+        return Ok(String::from_utf8_lossy(&out[..end]).into_owned());
+    }
+    match abi::decode(&[ParamType::String], &out)?.pop() {
+        Some(Token::String(s)) => Ok(s),
+        _ => Err(format!("unexpected return data from {:#x}", token).into()),
+    }
+}
+
+/// Read `decimals()`, `symbol()` and `name()` of `token`.
+pub async fn fetch_metadata<S>(state: &S, token: Address) -> Result<TokenMetadata, Box<dyn Error>>
+where
+    S: ChainState + Sync,
+{
+    Ok(TokenMetadata {
+        decimals: blockchain::token_decimals(state, token).await?,
+        symbol: call_string(state, token, [0x95, 0xd8, 0x9b, 0x41]).await?,
+        name: call_string(state, token, [0x06, 0xfd, 0xde, 0x03]).await?,
+    })
+}
+
+/// Token metadata keyed by contract address, persisted as JSON
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MetadataCache {
+    tokens: HashMap<Address, TokenMetadata>,
+    /// Tokens whose metadata could not be read, retried in the next run only
+    #[serde(skip)]
+    failed: HashSet<Address>,
+}
+
+impl MetadataCache {
+    /// Load the cache from `path`, starting empty if the file does not exist.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn get(&self, token: &Address) -> Option<&TokenMetadata> {
+        self.tokens.get(token)
+    }
+
+    /// Return the metadata of `token`, querying the chain on a cache miss. A
+    /// token that failed once is not queried again.
+    pub async fn resolve<S>(
+        &mut self,
+        state: &S,
+        token: Address,
+    ) -> Result<&TokenMetadata, Box<dyn Error>>
+    where
+        S: ChainState + Sync,
+    {
+        if self.failed.contains(&token) {
+            return Err(format!("metadata of {:#x} could not be read", token).into());
+        }
+        Ok(match self.tokens.entry(token) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => match fetch_metadata(state, token).await {
+                Ok(metadata) => e.insert(metadata),
+                Err(err) => {
+                    self.failed.insert(token);
+                    return Err(err);
+                }
+            },
+        })
+    }
+}
+
+/// A metadata field reported differently by the explorer and the contract
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mismatch {
+    pub token: Address,
+    pub field: &'static str,
+    pub explorer: String,
+    pub on_chain: String,
+}
+
+/// Replace the explorer-reported decimals, symbol and name of every transfer in
+/// `txs` with the values read from the token contract and return each field that
+/// differed, once per token. Tokens whose metadata cannot be read keep the
+/// explorer values.
+pub async fn apply_metadata<S>(
+    state: &S,
+    txs: &mut [Transaction],
+    cache: &mut MetadataCache,
+) -> Result<Vec<Mismatch>, Box<dyn Error>>
+where
+    S: ChainState + Sync,
+{
+    let mut res: Vec<Mismatch> = Vec::new();
+    for tr in txs.iter_mut().flat_map(|tx| tx.transfers.iter_mut()) {
+        let Ok(metadata) = cache.resolve(state, tr.token_contract).await else {
+            continue;
+        };
+        let fields = [
+            (
+                "decimals",
+                &mut tr.token_decimal,
+                metadata.decimals.to_string(),
+            ),
+            ("symbol", &mut tr.token_symbol, metadata.symbol.clone()),
+            ("name", &mut tr.token_name, metadata.name.clone()),
+        ];
+        for (field, explorer, on_chain) in fields {
+            if *explorer == on_chain {
+                continue;
+            }
+            let seen = res
+                .iter()
+                .any(|m| m.token == tr.token_contract && m.field == field);
+            if !seen {
+                res.push(Mismatch {
+                    token: tr.token_contract,
+                    field,
+                    explorer: explorer.clone(),
+                    on_chain: on_chain.clone(),
+                });
+            }
+            *explorer = on_chain;
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::test_util::MockState;
+    use crate::blockchain::Erc20Transfer;
+    use ethers::types::U256;
+    use std::env;
+
+    /// USDC-like token at 0x33…, while calls to any other contract revert
+    fn usdc() -> MockState {
+        let mut state = MockState::constant(U256::zero());
+        state
+            .tokens
+            .insert(Address::repeat_byte(0x33), ("USD Coin", "USDC", 6));
+        state
+    }
+
+    #[tokio::test]
+    async fn apply_metadata_flags_mismatches() {
+        let transfer = Erc20Transfer {
+            token_contract: Address::repeat_byte(0x33),
+            from: Address::zero(),
+            to: None,
+            value: U256::one(),
+            token_name: "USD Coin".to_string(),
+            token_symbol: "USDC".to_string(),
+            token_decimal: "18".to_string(),
+            counterparty: None,
+        };
+        let mut txs = vec![Transaction {
+            transfers: vec![transfer.clone(), transfer],
+            ..Default::default()
+        }];
+        let mut cache = MetadataCache::default();
+        let res = apply_metadata(&usdc(), &mut txs, &mut cache).await.unwrap();
+        assert_eq!(
+            res,
+            vec![Mismatch {
+                token: Address::repeat_byte(0x33),
+                field: "decimals",
+                explorer: "18".to_string(),
+                on_chain: "6".to_string(),
+            }]
+        );
+        assert!(txs[0].transfers.iter().all(|t| t.token_decimal == "6"));
+
+        let path = env::temp_dir().join("token_metadata_test.json");
+        cache.save(&path).unwrap();
+        let loaded = MetadataCache::load(&path).unwrap();
+        let _ = fs::remove_file(path);
+        assert_eq!(
+            loaded.get(&Address::repeat_byte(0x33)).unwrap().name,
+            "USD Coin"
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_lookups_are_cached() {
+        let transfer = Erc20Transfer {
+            token_contract: Address::repeat_byte(0x44),
+            from: Address::zero(),
+            to: None,
+            value: U256::one(),
+            token_name: "Unknown".to_string(),
+            token_symbol: "UNK".to_string(),
+            token_decimal: "18".to_string(),
+            counterparty: None,
+        };
+        let mut txs = vec![Transaction {
+            transfers: vec![transfer.clone(), transfer],
+            ..Default::default()
+        }];
+        let token = usdc();
+        let mut cache = MetadataCache::default();
+        let res = apply_metadata(&token, &mut txs, &mut cache).await.unwrap();
+        assert!(res.is_empty());
+        assert_eq!(token.calls(), 1);
+        assert_eq!(txs[0].transfers[1].token_symbol, "UNK");
+    }
+}
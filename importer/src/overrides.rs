@@ -0,0 +1,98 @@
+use ethers::types::H256;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+use crate::blockchain::{self, Transaction};
+
+/// Manual adjustments for a single transaction
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Override {
+    pub category: Option<String>,
+    pub description: Option<String>,
+    /// GnuCash account used for every split of the transaction
+    pub account: Option<String>,
+    /// GnuCash accounts for individual splits keyed by commodity
+    pub splits: HashMap<String, String>,
+    /// Drop the transaction from the export entirely
+    pub ignore: bool,
+}
+
+/// Mapping from transaction hashes to manual overrides
+#[derive(Debug, Default, Deserialize)]
+pub struct Overrides(pub HashMap<H256, Override>);
+
+impl Overrides {
+    /// Load overrides from the given file path. The format is inferred from the
+    /// extension and may be TOML, JSON, or YAML.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        blockchain::load_structured(path)
+    }
+}
+
+/// Apply manual overrides to `txs`, removing ignored transactions. Overrides are
+/// keyed by transaction hash so they keep applying after transactions are fetched
+/// again.
+pub fn apply_overrides(txs: &mut Vec<Transaction>, overrides: &Overrides) {
+    txs.retain(|tx| !overrides.0.get(&tx.hash).is_some_and(|o| o.ignore));
+    for tx in txs.iter_mut() {
+        let Some(o) = overrides.0.get(&tx.hash) else {
+            continue;
+        };
+        if o.category.is_some() {
+            tx.category = o.category.clone();
+        }
+        if o.description.is_some() {
+            tx.description = o.description.clone();
+        }
+        if o.account.is_some() {
+            tx.account = o.account.clone();
+        }
+        tx.split_accounts
+            .extend(o.splits.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::from_chain;
+    use ethers::types::{Address, U256};
+
+    #[test]
+    fn overrides_apply_and_ignore() {
+        let overrides: Overrides = toml::from_str(
+            r#"
+            ["0x0000000000000000000000000000000000000000000000000000000000000001"]
+            description = "Lunch"
+            account = "Expenses:Food"
+            splits = { ETH = "Expenses:Tips" }
+
+            ["0x0000000000000000000000000000000000000000000000000000000000000002"]
+            ignore = true
+            "#,
+        )
+        .unwrap();
+
+        let address = Address::repeat_byte(0x11);
+        let tx = |n: u64| Transaction {
+            hash: H256::from_low_u64_be(n),
+            from: address,
+            value: U256::exp10(18),
+            category: Some("Payment".to_string()),
+            ..Default::default()
+        };
+        let mut txs = vec![tx(1), tx(2), tx(3)];
+        apply_overrides(&mut txs, &overrides);
+        assert_eq!(txs.len(), 2);
+        assert_eq!(txs[0].description.as_deref(), Some("Lunch"));
+        assert_eq!(txs[0].category.as_deref(), Some("Payment"));
+        assert_eq!(txs[1].hash, H256::from_low_u64_be(3));
+
+        let splits = from_chain(address, &txs);
+        assert_eq!(splits[0].account, "Expenses:Tips");
+        assert_eq!(splits[1].account, "Payment");
+    }
+}
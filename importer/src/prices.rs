@@ -0,0 +1,82 @@
+use chrono::NaiveDate;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::path::Path;
+
+/// A single row of a price file
+#[derive(Debug, Deserialize)]
+struct PriceRecord {
+    date: NaiveDate,
+    commodity: String,
+    price: f64,
+}
+
+/// Historical fiat prices for commodities keyed by date
+#[derive(Debug, Default)]
+pub struct PriceTable(HashMap<String, BTreeMap<NaiveDate, f64>>);
+
+impl PriceTable {
+    /// Load prices from a CSV file with `date`, `commodity` and `price` columns.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut rdr = csv::Reader::from_path(path)?;
+        let mut table = Self::default();
+        for record in rdr.deserialize() {
+            let record: PriceRecord = record?;
+            table.insert(&record.commodity, record.date, record.price);
+        }
+        Ok(table)
+    }
+
+    /// Record the price of `commodity` on `date`.
+    pub fn insert(&mut self, commodity: &str, date: NaiveDate, price: f64) {
+        self.0
+            .entry(commodity.to_string())
+            .or_default()
+            .insert(date, price);
+    }
+
+    /// Return the most recent price of `commodity` on or before `date`.
+    pub fn price(&self, commodity: &str, date: NaiveDate) -> Option<f64> {
+        self.0
+            .get(commodity)?
+            .range(..=date)
+            .next_back()
+            .map(|(_, p)| *p)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn price_uses_latest_known_date() {
+        let mut table = PriceTable::default();
+        table.insert("ETH", NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), 1000.0);
+        table.insert("ETH", NaiveDate::from_ymd_opt(2023, 1, 3).unwrap(), 1200.0);
+
+        let day = |d| NaiveDate::from_ymd_opt(2023, 1, d).unwrap();
+        assert_eq!(table.price("ETH", day(2)), Some(1000.0));
+        assert_eq!(table.price("ETH", day(3)), Some(1200.0));
+        assert_eq!(
+            table.price("ETH", NaiveDate::from_ymd_opt(2022, 12, 31).unwrap()),
+            None
+        );
+        assert_eq!(table.price("USDC", day(3)), None);
+    }
+
+    #[test]
+    fn load_reads_csv() {
+        let path = env::temp_dir().join("prices_test.csv");
+        fs::write(&path, "date,commodity,price\n2023-01-01,ETH,1000.5\n").unwrap();
+        let table = PriceTable::load(&path).unwrap();
+        let _ = fs::remove_file(path);
+        assert_eq!(
+            table.price("ETH", NaiveDate::from_ymd_opt(2023, 6, 1).unwrap()),
+            Some(1000.5)
+        );
+    }
+}
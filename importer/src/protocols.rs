@@ -0,0 +1,1034 @@
+use ethers::types::{Address, U256};
+use once_cell::sync::Lazy;
+
+use crate::blockchain::Transaction;
+use crate::bridge;
+use crate::events::DecodedEvent;
+use crate::export::{liability_account, liability_amount, value_to_f64};
+use crate::tokens::{self, TokenKind};
+
+/// Change of a single commodity held by the tracked address within a transaction
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movement {
+    /// Token contract, `None` for native ETH
+    pub token: Option<Address>,
+    pub commodity: String,
+    pub kind: TokenKind,
+    /// Signed change of the wallet balance
+    pub amount: f64,
+    /// Unsigned raw amount in the token's smallest unit
+    pub value: U256,
+    pub decimals: u32,
+}
+
+/// Return the changes of ETH and whitelisted tokens held by `address` in `tx`.
+pub fn movements(address: Address, tx: &Transaction) -> Vec<Movement> {
+    let mut res = Vec::new();
+    let eth = value_to_f64(tx.value, 18);
+    if eth != 0.0 && (tx.from == address || tx.to == Some(address)) {
+        res.push(Movement {
+            token: None,
+            commodity: "ETH".to_string(),
+            kind: TokenKind::Asset,
+            amount: if tx.from == address { -eth } else { eth },
+            value: tx.value,
+            decimals: 18,
+        });
+    }
+    for tr in &tx.transfers {
+        let Some(info) = tokens::GOOD_TOKENS.get(&tr.token_contract) else {
+            continue;
+        };
+        let decimals = tr.token_decimal.parse::<u32>().unwrap_or(18);
+        let amount = value_to_f64(tr.value, decimals);
+        let amount = if tr.from == address {
+            -amount
+        } else if tr.to == Some(address) {
+            amount
+        } else {
+            continue;
+        };
+        res.push(Movement {
+            token: Some(tr.token_contract),
+            commodity: info.symbol.to_string(),
+            kind: info.kind,
+            amount,
+            value: tr.value,
+            decimals,
+        });
+    }
+    res
+}
+
+/// A single split of a restructured transaction. The amount is the change of the
+/// wallet booked against `account`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Leg {
+    pub account: String,
+    pub commodity: String,
+    pub amount: f64,
+}
+
+/// A transaction restructured by a [`ProtocolAdapter`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Structured {
+    pub description: String,
+    pub legs: Vec<Leg>,
+    /// Account receiving NFTs moved by the transaction, such as a position NFT
+    pub nft_account: Option<String>,
+}
+
+/// Recognizes interactions with a DeFi protocol and books them as structured
+/// GnuCash transactions instead of raw token movements.
+pub trait ProtocolAdapter {
+    fn name(&self) -> &'static str;
+
+    /// Restructure `tx` if it is an interaction of `address` with the protocol.
+    fn structure(&self, address: Address, tx: &Transaction) -> Option<Structured>;
+}
+
+/// Return the built-in adapters in the order they are consulted. Uniswap comes
+/// last since other protocols route swaps through its pools.
+pub fn builtin_adapters() -> Vec<Box<dyn ProtocolAdapter>> {
+    vec![
+        Box::new(Weth),
+        Box::new(ArbitrumBridge),
+        Box::new(Aave),
+        Box::new(Gmx),
+        Box::new(Uniswap),
+    ]
+}
+
+fn find_event<'a>(tx: &'a Transaction, names: &[&str]) -> Option<&'a DecodedEvent> {
+    tx.events.iter().find(|e| names.contains(&e.name.as_str()))
+}
+
+fn method_name(tx: &Transaction) -> Option<&str> {
+    tx.decoded
+        .as_ref()
+        .map(|c| c.name.as_str())
+        .or(tx.guessed_method.as_deref())
+        .or_else(|| {
+            tx.function_name
+                .as_deref()
+                .and_then(|f| f.split('(').next())
+        })
+}
+
+/// Book every movement of `kinds` against the account returned by `account`.
+fn legs_for(
+    address: Address,
+    tx: &Transaction,
+    kinds: &[TokenKind],
+    account: impl Fn(&Movement) -> String,
+) -> Vec<Leg> {
+    movements(address, tx)
+        .into_iter()
+        .filter(|m| kinds.contains(&m.kind))
+        .map(|m| Leg {
+            account: account(&m),
+            commodity: m.commodity.clone(),
+            amount: m.amount,
+        })
+        .collect()
+}
+
+/// Wrapped Ether contract on Arbitrum
+static WETH: Lazy<Address> = Lazy::new(|| {
+    "0x82af49447d8a07e3bd95bd0d56f35241523fbab1"
+        .parse()
+        .expect("valid address")
+});
+
+/// Wrapping and unwrapping ETH with direct calls to the WETH contract. Both are
+/// booked as a 1:1 conversion between the ETH and WETH wallet accounts.
+pub struct Weth;
+
+impl ProtocolAdapter for Weth {
+    fn name(&self) -> &'static str {
+        "WETH"
+    }
+
+    fn structure(&self, address: Address, tx: &Transaction) -> Option<Structured> {
+        if tx.from != address || tx.to != Some(*WETH) {
+            return None;
+        }
+        let (description, from, to, value) = match tx.input.get(..4) {
+            Some([0x2e, 0x1a, 0x7d, 0x4d]) => (
+                "Unwrap WETH",
+                "WETH",
+                "ETH",
+                U256::from_big_endian(tx.input.get(4..36)?),
+            ),
+            None | Some([0xd0, 0xe3, 0x0d, 0xb0]) if !tx.value.is_zero() => {
+                ("Wrap ETH", "ETH", "WETH", tx.value)
+            }
+            _ => return None,
+        };
+        let amount = value_to_f64(value, 18);
+        Some(Structured {
+            description: description.to_string(),
+            legs: vec![
+                Leg {
+                    account: format!("Assets:Wallet:{}", from),
+                    commodity: from.to_string(),
+                    amount: -amount,
+                },
+                Leg {
+                    account: format!("Assets:Wallet:{}", to),
+                    commodity: to.to_string(),
+                    amount,
+                },
+            ],
+            nft_account: None,
+        })
+    }
+}
+
+/// Deposits from and withdrawals to Ethereum through the Arbitrum bridge. Funds
+/// are booked to [`bridge::FUNDS_IN_TRANSIT`] until they arrive on the other
+/// chain.
+pub struct ArbitrumBridge;
+
+impl ProtocolAdapter for ArbitrumBridge {
+    fn name(&self) -> &'static str {
+        "Arbitrum bridge"
+    }
+
+    fn structure(&self, address: Address, tx: &Transaction) -> Option<Structured> {
+        let description = if bridge::is_l2_withdrawal(tx) {
+            "Bridge withdrawal to Ethereum"
+        } else if bridge::is_l2_deposit(address, tx) {
+            "Bridge deposit from Ethereum"
+        } else {
+            return None;
+        };
+        let mut legs = legs_for(address, tx, &[TokenKind::Asset], |_| {
+            bridge::FUNDS_IN_TRANSIT.to_string()
+        });
+        // ETH deposits are sent by the wallet to itself on L1 and arrive as such
+        if tx.from == address && tx.to == Some(address) {
+            for leg in legs.iter_mut().filter(|l| l.commodity == "ETH") {
+                leg.amount = leg.amount.abs();
+            }
+        }
+        Some(Structured {
+            description: description.to_string(),
+            legs,
+            nft_account: None,
+        })
+    }
+}
+
+/// Account the tokens borrowed from and repaid to Aave go through
+const AAVE_TRADING: &str = "Trading:Aave";
+
+/// Aave V3 lending pool. Supplies and withdrawals convert the reserve into its
+/// aToken, so both legs are booked on the position account of the reserve and
+/// the aToken balance, interest included, stays in the wallet. Debt tokens are
+/// booked on their liability account the same way
+/// [`export::from_chain`](crate::export::from_chain) books them, and the
+/// borrowed or repaid tokens go through [`AAVE_TRADING`].
+pub struct Aave;
+
+impl ProtocolAdapter for Aave {
+    fn name(&self) -> &'static str {
+        "Aave"
+    }
+
+    fn structure(&self, address: Address, tx: &Transaction) -> Option<Structured> {
+        let event = find_event(tx, &["Supply", "Withdraw", "Borrow", "Repay"])
+            .filter(|e| e.arg("reserve").is_some())?;
+        let (action, debt) = match event.name.as_str() {
+            "Supply" => ("supply", false),
+            "Withdraw" => ("withdraw", false),
+            "Borrow" => ("borrow", true),
+            _ => ("repay", true),
+        };
+        let legs = if debt {
+            movements(address, tx)
+                .into_iter()
+                .map(|m| match m.kind {
+                    TokenKind::Liability => Leg {
+                        account: liability_account(&m.commodity),
+                        commodity: m.commodity,
+                        amount: liability_amount(m.amount),
+                    },
+                    _ => Leg {
+                        account: AAVE_TRADING.to_string(),
+                        commodity: m.commodity,
+                        amount: m.amount,
+                    },
+                })
+                .collect()
+        } else {
+            let reserve = event
+                .arg("reserve")
+                .and_then(|r| r.parse::<Address>().ok())
+                .and_then(|r| tokens::get_symbol(&r));
+            let kinds = [TokenKind::Asset, TokenKind::Receipt];
+            legs_for(address, tx, &kinds, |m| {
+                format!("Assets:Aave:{}", reserve.unwrap_or(&m.commodity))
+            })
+        };
+        Some(Structured {
+            description: format!("Aave {}", action),
+            legs,
+            nft_account: None,
+        })
+    }
+}
+
+/// Uniswap pools and the V3 position manager. Every V3 position is tracked on
+/// its own asset account and the part of collected tokens exceeding the removed
+/// liquidity is booked as fee income.
+pub struct Uniswap;
+
+/// Account collecting Uniswap V3 trading fees
+const UNISWAP_FEES: &str = "Income:Uniswap V3:Fees";
+
+/// Return the two tokens of the pool as far as `collect` pays them out. Uniswap
+/// orders the tokens of a pool by address, so token0 is the lower one. A token
+/// paid out alone is placed by the side of `collect` that is not zero.
+fn pool_tokens(collect: &DecodedEvent, paid: &[Address]) -> [Option<Address>; 2] {
+    let mut paid = paid.to_vec();
+    paid.sort();
+    paid.dedup();
+    let is_zero = |name| {
+        collect
+            .arg(name)
+            .and_then(|a| U256::from_dec_str(a).ok())
+            .unwrap_or_default()
+            .is_zero()
+    };
+    match paid[..] {
+        [token0, token1] => [Some(token0), Some(token1)],
+        [token] => match (is_zero("amount0"), is_zero("amount1")) {
+            (false, true) => [Some(token), None],
+            (true, false) => [None, Some(token)],
+            _ => [None, None],
+        },
+        _ => [None, None],
+    }
+}
+
+/// Split the raw `collected` amount of `token` into returned liquidity and fees.
+fn principal_split(
+    decrease: Option<&DecodedEvent>,
+    pool: [Option<Address>; 2],
+    token: Address,
+    collected: U256,
+) -> (U256, U256) {
+    let Some(index) = pool.iter().position(|t| *t == Some(token)) else {
+        return (collected, U256::zero());
+    };
+    let principal = decrease
+        .and_then(|e| e.arg(["amount0", "amount1"][index]))
+        .and_then(|a| U256::from_dec_str(a).ok())
+        .unwrap_or_default()
+        .min(collected);
+    (principal, collected - principal)
+}
+
+impl Uniswap {
+    fn position(&self, address: Address, tx: &Transaction, id: &str) -> Structured {
+        let account = format!("Assets:Uniswap V3:Position #{}", id);
+        let increase = find_event(tx, &["IncreaseLiquidity"]);
+        let decrease = find_event(tx, &["DecreaseLiquidity"]);
+        let collect = find_event(tx, &["Collect"]).filter(|e| e.arg("tokenId").is_some());
+        let description = match (increase, decrease) {
+            (Some(_), _) => format!("Uniswap V3 add liquidity to position #{}", id),
+            (None, Some(_)) => format!("Uniswap V3 remove liquidity from position #{}", id),
+            (None, None) => format!("Uniswap V3 collect fees from position #{}", id),
+        };
+
+        let moved: Vec<_> = movements(address, tx)
+            .into_iter()
+            .filter(|m| m.kind != TokenKind::Liability)
+            .collect();
+        // ETH is paid out by the pool as WETH and unwrapped by the manager
+        let token = |m: &Movement| m.token.unwrap_or(*WETH);
+        let paid: Vec<_> = moved.iter().filter(|m| m.amount > 0.0).map(token).collect();
+        let pool = collect.map(|c| pool_tokens(c, &paid));
+
+        let mut legs = Vec::new();
+        for m in moved {
+            let (principal, fees) = match pool {
+                Some(pool) if m.amount > 0.0 => {
+                    let (principal, fees) = principal_split(decrease, pool, token(&m), m.value);
+                    (
+                        value_to_f64(principal, m.decimals),
+                        value_to_f64(fees, m.decimals),
+                    )
+                }
+                _ => (m.amount, 0.0),
+            };
+            if principal != 0.0 {
+                legs.push(Leg {
+                    account: account.clone(),
+                    commodity: m.commodity.clone(),
+                    amount: principal,
+                });
+            }
+            if fees != 0.0 {
+                legs.push(Leg {
+                    account: UNISWAP_FEES.to_string(),
+                    commodity: m.commodity,
+                    amount: fees,
+                });
+            }
+        }
+        Structured {
+            description,
+            legs,
+            nft_account: Some(account),
+        }
+    }
+}
+
+impl ProtocolAdapter for Uniswap {
+    fn name(&self) -> &'static str {
+        "Uniswap"
+    }
+
+    fn structure(&self, address: Address, tx: &Transaction) -> Option<Structured> {
+        let position = find_event(tx, &["IncreaseLiquidity", "DecreaseLiquidity", "Collect"])
+            .and_then(|e| e.arg("tokenId"));
+        if let Some(id) = position {
+            return Some(self.position(address, tx, id));
+        }
+        find_event(tx, &["Swap"])?;
+        let kinds = [TokenKind::Asset, TokenKind::Receipt];
+        Some(Structured {
+            description: "Uniswap swap".to_string(),
+            legs: legs_for(address, tx, &kinds, |_| "Trading:Uniswap".to_string()),
+            nft_account: None,
+        })
+    }
+}
+
+/// GMX asset account holding position collateral
+const GMX_MARGIN: &str = "Assets:GMX:Margin";
+/// GMX account receiving realized profits and losses
+const GMX_PNL: &str = "Income:GMX:Realized PnL";
+/// GMX account paying position, funding and borrow fees
+const GMX_FEES: &str = "Expenses:GMX:Fees";
+
+/// GMX perpetual positions, recognized from position events of direct router
+/// calls or from position requests to the position router. Collateral is held on
+/// a margin account. When a position is decreased the tokens received are split
+/// into returned collateral, realized PnL and fees in proportion to the USD
+/// amounts of the position events. Realized losses are taken from the margin
+/// account without a payout.
+pub struct Gmx;
+
+fn usd(e: &DecodedEvent, name: &str) -> f64 {
+    e.arg(name).and_then(|v| v.parse().ok()).unwrap_or(0.0)
+}
+
+fn push_leg(legs: &mut Vec<Leg>, account: &str, commodity: &str, amount: f64) {
+    if amount != 0.0 {
+        legs.push(Leg {
+            account: account.to_string(),
+            commodity: commodity.to_string(),
+            amount,
+        });
+    }
+}
+
+impl ProtocolAdapter for Gmx {
+    fn name(&self) -> &'static str {
+        "GMX"
+    }
+
+    fn structure(&self, address: Address, tx: &Transaction) -> Option<Structured> {
+        let is_own = |e: &DecodedEvent| {
+            e.arg("account")
+                .and_then(|a| a.parse::<Address>().ok())
+                .is_some_and(|a| a == address)
+        };
+        // UpdatePnl and ClosePosition name the position only by its key
+        let keys: Vec<&str> = tx
+            .events
+            .iter()
+            .filter(|e| is_own(e))
+            .filter_map(|e| e.arg("key"))
+            .collect();
+        let own = |e: &&DecodedEvent| match e.arg("account") {
+            Some(_) => is_own(e),
+            None => e.arg("key").is_none_or(|k| keys.contains(&k)),
+        };
+        let events: Vec<&DecodedEvent> = tx.events.iter().filter(own).collect();
+        let named = |name: &'static str| events.iter().copied().filter(move |e| e.name == name);
+        let sum = |name: &'static str, arg: &str| named(name).map(|e| usd(e, arg)).sum::<f64>();
+
+        let increased = named("IncreasePosition").next().is_some();
+        let decreased = named("DecreasePosition").next().is_some();
+        let liquidated = named("LiquidatePosition").next().is_some();
+        let method = method_name(tx).unwrap_or_default();
+        let description =
+            if increased || (!decreased && method.starts_with("createIncreasePosition")) {
+                "GMX open position"
+            } else if decreased || method.starts_with("createDecreasePosition") {
+                "GMX close position"
+            } else if liquidated {
+                "GMX liquidation"
+            } else {
+                return None;
+            };
+
+        let mut legs = Vec::new();
+        let moved = movements(address, tx)
+            .into_iter()
+            .filter(|m| m.kind == TokenKind::Asset);
+        if increased {
+            let collateral = sum("IncreasePosition", "collateralDelta");
+            let fee = sum("IncreasePosition", "fee");
+            let fee_share = if collateral > 0.0 {
+                fee / collateral
+            } else {
+                0.0
+            };
+            for m in moved {
+                let fee = if m.amount < 0.0 {
+                    m.amount * fee_share
+                } else {
+                    0.0
+                };
+                push_leg(&mut legs, GMX_MARGIN, &m.commodity, m.amount - fee);
+                push_leg(&mut legs, GMX_FEES, &m.commodity, fee);
+            }
+        } else if decreased {
+            let fee = sum("DecreasePosition", "fee");
+            let pnl = |profit: bool| {
+                named("UpdatePnl")
+                    .filter(|e| (e.arg("hasProfit") == Some("true")) == profit)
+                    .map(|e| usd(e, "delta"))
+                    .sum::<f64>()
+            };
+            let (profit, loss) = (pnl(true), pnl(false));
+            // A loss is taken from the collateral left on the position. Closing
+            // the position pays out whatever collateral remains after it.
+            let closed = named("ClosePosition").next().is_some();
+            let collateral = sum("DecreasePosition", "collateralDelta")
+                + sum("ClosePosition", "collateral")
+                - if closed { loss } else { 0.0 };
+            let received = collateral + profit - fee;
+            for m in moved {
+                if m.amount > 0.0 && received > 0.0 {
+                    let unit = m.amount / received;
+                    push_leg(&mut legs, GMX_MARGIN, &m.commodity, unit * collateral);
+                    push_leg(&mut legs, GMX_PNL, &m.commodity, unit * profit);
+                    push_leg(&mut legs, GMX_FEES, &m.commodity, -unit * fee);
+                    // the loss moves from the margin account to the PnL account
+                    push_leg(&mut legs, GMX_MARGIN, &m.commodity, unit * loss);
+                    push_leg(&mut legs, GMX_PNL, &m.commodity, -unit * loss);
+                } else {
+                    push_leg(&mut legs, GMX_FEES, &m.commodity, m.amount);
+                }
+            }
+        } else {
+            // Position requests only pay collateral and the keeper's execution
+            // fee, the position itself is changed in a later keeper transaction.
+            let account = if description == "GMX open position" {
+                GMX_MARGIN
+            } else {
+                GMX_FEES
+            };
+            for m in moved {
+                push_leg(&mut legs, account, &m.commodity, m.amount);
+            }
+        }
+        Some(Structured {
+            description: description.to_string(),
+            legs,
+            nft_account: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::test_util::{MockClient, MockState};
+    use crate::blockchain::Erc20Transfer;
+    use crate::decoder::DecodedArg;
+    use crate::interest;
+    use ethers::types::U256;
+
+    const USDC: &str = "0xaf88d065e77c8cc2239327c5edb3a432268e5831";
+    const AUSDC: &str = "0x724dc807b04555b71ed48a6896b6f41593b8c637";
+    const WETH_TOKEN: &str = "0x82af49447d8a07e3bd95bd0d56f35241523fbab1";
+
+    fn transfer(token: &str, from: Address, to: Address, value: u64) -> Erc20Transfer {
+        Erc20Transfer {
+            token_contract: token.parse().unwrap(),
+            from,
+            to: Some(to),
+            value: U256::from(value),
+            token_name: String::new(),
+            token_symbol: String::new(),
+            token_decimal: "0".to_string(),
+            counterparty: None,
+        }
+    }
+
+    fn event(name: &str, args: &[(&str, &str)]) -> DecodedEvent {
+        DecodedEvent {
+            address: Address::repeat_byte(0x99),
+            name: name.to_string(),
+            args: args
+                .iter()
+                .map(|(n, v)| DecodedArg {
+                    name: n.to_string(),
+                    value: v.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn aave_supply_converts_into_receipt_token() {
+        let me = Address::repeat_byte(0x11);
+        let pool = Address::repeat_byte(0x99);
+        let tx = Transaction {
+            from: me,
+            to: Some(pool),
+            transfers: vec![
+                transfer(USDC, me, pool, 100),
+                transfer(AUSDC, Address::zero(), me, 100),
+            ],
+            events: vec![event("Supply", &[("reserve", USDC)])],
+            ..Default::default()
+        };
+        let res = Aave.structure(me, &tx).unwrap();
+        assert_eq!(res.description, "Aave supply");
+        let leg = |commodity: &str, amount: f64| Leg {
+            account: "Assets:Aave:USDC".to_string(),
+            commodity: commodity.to_string(),
+            amount,
+        };
+        assert_eq!(res.legs, vec![leg("USDC", -100.0), leg("aUSDC", 100.0)]);
+        assert!(Uniswap.structure(me, &tx).is_none());
+    }
+
+    #[tokio::test]
+    async fn aave_receipt_nets_to_zero_with_interest() {
+        let me = Address::repeat_byte(0x11);
+        let pool = Address::repeat_byte(0x99);
+        let action = |day: u64, name: &str, transfers: Vec<Erc20Transfer>| Transaction {
+            timestamp: day * 86_400,
+            from: me,
+            to: Some(pool),
+            transfers,
+            events: vec![event(name, &[("reserve", USDC)])],
+            ..Default::default()
+        };
+        let txs = [
+            action(
+                1,
+                "Supply",
+                vec![
+                    transfer(USDC, me, pool, 100),
+                    transfer(AUSDC, Address::zero(), me, 100),
+                ],
+            ),
+            action(
+                3,
+                "Withdraw",
+                vec![
+                    transfer(AUSDC, me, Address::zero(), 102),
+                    transfer(USDC, pool, me, 102),
+                ],
+            ),
+        ];
+        // one unit of interest a day until the withdrawal on day 3
+        let state = MockState::new(|_, block| {
+            let day = block.unwrap_or_default();
+            U256::from(if (1..3).contains(&day) { 99 + day } else { 0 })
+        });
+        let until = chrono::NaiveDate::from_ymd_opt(1970, 1, 5).unwrap();
+        let client = MockClient::with_block_time(86_400);
+        let daily = interest::Granularity::Daily;
+        let accrued = interest::accrue(&client, &state, me, &txs, daily, until)
+            .await
+            .unwrap();
+
+        let legs = txs
+            .iter()
+            .flat_map(|tx| Aave.structure(me, tx).unwrap().legs);
+        let booked: f64 = legs
+            .filter(|l| l.commodity == "aUSDC")
+            .map(|l| l.amount)
+            .chain(accrued.iter().map(|s| s.amount))
+            .sum();
+        assert_eq!(accrued.len(), 2);
+        assert!(accrued.iter().all(|s| s.commodity == "aUSDC"));
+        assert_eq!(booked, 0.0);
+    }
+
+    #[test]
+    fn aave_borrow_books_debt_like_plain_exports() {
+        let me = Address::repeat_byte(0x11);
+        let pool = Address::repeat_byte(0x99);
+        let debt = "0xf611aeb5013fd2c0511c9cd55c7dc5c1140741a6";
+        let tx = Transaction {
+            from: me,
+            to: Some(pool),
+            transfers: vec![
+                transfer(debt, Address::zero(), me, 100),
+                transfer(USDC, pool, me, 100),
+            ],
+            events: vec![event("Borrow", &[("reserve", USDC)])],
+            ..Default::default()
+        };
+        let res = Aave.structure(me, &tx).unwrap();
+        assert_eq!(res.description, "Aave borrow");
+        let plain = crate::export::from_chain(
+            me,
+            &[Transaction {
+                account: Some(AAVE_TRADING.to_string()),
+                ..tx
+            }],
+        );
+        let plain: Vec<Leg> = plain
+            .into_iter()
+            .map(|s| Leg {
+                account: s.account,
+                commodity: s.commodity,
+                amount: s.amount,
+            })
+            .collect();
+        assert_eq!(res.legs, plain);
+        assert_eq!(res.legs[0].account, "Liabilities:Debt USDC");
+        assert_eq!(res.legs[0].amount, -100.0);
+    }
+
+    #[test]
+    fn uniswap_swap_books_trading_account() {
+        let me = Address::repeat_byte(0x11);
+        let pool = Address::repeat_byte(0x99);
+        let tx = Transaction {
+            from: me,
+            to: Some(pool),
+            transfers: vec![
+                transfer(USDC, me, pool, 100),
+                transfer(WETH_TOKEN, pool, me, 1),
+            ],
+            events: vec![event("Swap", &[])],
+            ..Default::default()
+        };
+        let res = Uniswap.structure(me, &tx).unwrap();
+        assert_eq!(res.legs.len(), 2);
+        assert!(res.legs.iter().all(|l| l.account == "Trading:Uniswap"));
+        assert!(Aave.structure(me, &tx).is_none());
+    }
+
+    #[test]
+    fn weth_unwrap_is_a_conversion() {
+        let me = Address::repeat_byte(0x11);
+        let mut input = vec![0x2e, 0x1a, 0x7d, 0x4d];
+        input.extend(ethers::abi::encode(&[ethers::abi::Token::Uint(
+            U256::exp10(18),
+        )]));
+        let tx = Transaction {
+            from: me,
+            to: Some(*WETH),
+            input: input.into(),
+            transfers: vec![transfer(WETH_TOKEN, me, Address::zero(), 1)],
+            ..Default::default()
+        };
+        let res = Weth.structure(me, &tx).unwrap();
+        assert_eq!(res.description, "Unwrap WETH");
+        assert_eq!(
+            res.legs,
+            vec![
+                Leg {
+                    account: "Assets:Wallet:WETH".to_string(),
+                    commodity: "WETH".to_string(),
+                    amount: -1.0,
+                },
+                Leg {
+                    account: "Assets:Wallet:ETH".to_string(),
+                    commodity: "ETH".to_string(),
+                    amount: 1.0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn uniswap_collect_splits_fees_from_principal() {
+        let me = Address::repeat_byte(0x11);
+        let manager = Address::repeat_byte(0x99);
+        let tx = Transaction {
+            from: me,
+            to: Some(manager),
+            transfers: vec![
+                transfer(USDC, manager, me, 110),
+                transfer(WETH_TOKEN, manager, me, 3),
+            ],
+            events: vec![
+                event(
+                    "DecreaseLiquidity",
+                    &[("tokenId", "42"), ("amount0", "3"), ("amount1", "100")],
+                ),
+                event(
+                    "Collect",
+                    &[("tokenId", "42"), ("amount0", "3"), ("amount1", "110")],
+                ),
+            ],
+            ..Default::default()
+        };
+        let res = Uniswap.structure(me, &tx).unwrap();
+        assert_eq!(
+            res.description,
+            "Uniswap V3 remove liquidity from position #42"
+        );
+        let position = "Assets:Uniswap V3:Position #42";
+        assert_eq!(res.nft_account.as_deref(), Some(position));
+        let leg = |account: &str, commodity: &str, amount: f64| Leg {
+            account: account.to_string(),
+            commodity: commodity.to_string(),
+            amount,
+        };
+        assert_eq!(
+            res.legs,
+            vec![
+                leg(position, "USDC", 100.0),
+                leg(UNISWAP_FEES, "USDC", 10.0),
+                leg(position, "WETH", 3.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn uniswap_collect_maps_tokens_by_pool_order() {
+        let me = Address::repeat_byte(0x11);
+        let manager = Address::repeat_byte(0x99);
+        let tx = Transaction {
+            from: me,
+            to: Some(manager),
+            transfers: vec![
+                transfer(USDC, manager, me, 5),
+                transfer(WETH_TOKEN, manager, me, 5),
+            ],
+            events: vec![
+                event(
+                    "DecreaseLiquidity",
+                    &[("tokenId", "42"), ("amount0", "5"), ("amount1", "2")],
+                ),
+                event(
+                    "Collect",
+                    &[("tokenId", "42"), ("amount0", "5"), ("amount1", "5")],
+                ),
+            ],
+            ..Default::default()
+        };
+        let res = Uniswap.structure(me, &tx).unwrap();
+        let legs: Vec<_> = res
+            .legs
+            .iter()
+            .map(|l| (l.account.as_str(), l.commodity.as_str(), l.amount))
+            .collect();
+        let position = "Assets:Uniswap V3:Position #42";
+        assert_eq!(
+            legs,
+            vec![
+                (position, "USDC", 2.0),
+                (UNISWAP_FEES, "USDC", 3.0),
+                (position, "WETH", 5.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn bridge_withdrawal_goes_to_transit() {
+        let me = Address::repeat_byte(0x11);
+        let arb_sys: Address = "0x0000000000000000000000000000000000000064"
+            .parse()
+            .unwrap();
+        let tx = Transaction {
+            from: me,
+            to: Some(arb_sys),
+            value: U256::exp10(18),
+            ..Default::default()
+        };
+        let res = ArbitrumBridge.structure(me, &tx).unwrap();
+        assert_eq!(res.description, "Bridge withdrawal to Ethereum");
+        assert_eq!(res.legs[0].account, bridge::FUNDS_IN_TRANSIT);
+        assert_eq!(res.legs[0].amount, -1.0);
+
+        let deposit = Transaction { to: Some(me), ..tx };
+        let res = ArbitrumBridge.structure(me, &deposit).unwrap();
+        assert_eq!(res.description, "Bridge deposit from Ethereum");
+        assert_eq!(res.legs[0].amount, 1.0);
+
+        // token deposits are minted to the wallet by a retryable ticket
+        let usdc_e = "0xff970a61a04b1ca14834a43f5de4533ebddb5cc8";
+        let minted = Transaction {
+            from: Address::zero(),
+            to: Some(me),
+            transfers: vec![transfer(usdc_e, Address::zero(), me, 100)],
+            transfer_only: true,
+            ..Default::default()
+        };
+        let res = ArbitrumBridge.structure(me, &minted).unwrap();
+        assert_eq!(res.description, "Bridge deposit from Ethereum");
+        assert_eq!(res.legs[0].account, bridge::FUNDS_IN_TRANSIT);
+        assert_eq!(res.legs[0].amount, 100.0);
+
+        // native USDC minted through CCTP did not come through the gateways
+        let cctp = Transaction {
+            transfers: vec![transfer(USDC, Address::zero(), me, 100)],
+            ..minted
+        };
+        assert!(ArbitrumBridge.structure(me, &cctp).is_none());
+    }
+
+    #[test]
+    fn gmx_position_request_recognized_by_method() {
+        let me = Address::repeat_byte(0x11);
+        let tx = Transaction {
+            from: me,
+            value: U256::exp10(18),
+            guessed_method: Some("createIncreasePositionETH".to_string()),
+            ..Default::default()
+        };
+        let res = Gmx.structure(me, &tx).unwrap();
+        assert_eq!(res.description, "GMX open position");
+        assert_eq!(res.legs[0].account, GMX_MARGIN);
+        assert_eq!(res.legs[0].amount, -1.0);
+    }
+
+    #[test]
+    fn gmx_decrease_books_pnl_and_fees() {
+        let me = Address::repeat_byte(0x11);
+        let vault = Address::repeat_byte(0x99);
+        let account = format!("{:#x}", me);
+        let tx = Transaction {
+            from: me,
+            to: Some(vault),
+            transfers: vec![transfer(USDC, vault, me, 115)],
+            events: vec![
+                event("UpdatePnl", &[("hasProfit", "true"), ("delta", "20")]),
+                event(
+                    "DecreasePosition",
+                    &[
+                        ("account", &account),
+                        ("collateralDelta", "100"),
+                        ("fee", "5"),
+                    ],
+                ),
+            ],
+            ..Default::default()
+        };
+        let res = Gmx.structure(me, &tx).unwrap();
+        assert_eq!(res.description, "GMX close position");
+        let amounts: Vec<(&str, f64)> = res
+            .legs
+            .iter()
+            .map(|l| (l.account.as_str(), l.amount))
+            .collect();
+        assert_eq!(
+            amounts,
+            [(GMX_MARGIN, 100.0), (GMX_PNL, 20.0), (GMX_FEES, -5.0)]
+        );
+    }
+
+    fn decrease(me: Address, received: u64, events: Vec<DecodedEvent>) -> Vec<(&'static str, f64)> {
+        let vault = Address::repeat_byte(0x99);
+        let tx = Transaction {
+            from: me,
+            to: Some(vault),
+            transfers: vec![transfer(USDC, vault, me, received)],
+            events,
+            ..Default::default()
+        };
+        let legs = Gmx.structure(me, &tx).unwrap().legs;
+        let name = |account: &str| {
+            [GMX_MARGIN, GMX_PNL, GMX_FEES]
+                .into_iter()
+                .find(|a| *a == account)
+                .unwrap()
+        };
+        legs.iter().map(|l| (name(&l.account), l.amount)).collect()
+    }
+
+    #[test]
+    fn gmx_loss_is_taken_from_collateral() {
+        let me = Address::repeat_byte(0x11);
+        let account = format!("{:#x}", me);
+        let other = format!("{:#x}", Address::repeat_byte(0x22));
+        // a partial close pays out the collateral delta, the loss stays behind
+        // and the other trader's PnL in the same transaction is ignored
+        let amounts = decrease(
+            me,
+            95,
+            vec![
+                event(
+                    "UpdatePnl",
+                    &[("key", "0x01"), ("hasProfit", "false"), ("delta", "30")],
+                ),
+                event(
+                    "DecreasePosition",
+                    &[
+                        ("key", "0x01"),
+                        ("account", &account),
+                        ("collateralDelta", "100"),
+                        ("fee", "5"),
+                    ],
+                ),
+                event(
+                    "UpdatePnl",
+                    &[("key", "0x02"), ("hasProfit", "true"), ("delta", "50")],
+                ),
+                event("DecreasePosition", &[("key", "0x02"), ("account", &other)]),
+            ],
+        );
+        assert_eq!(
+            amounts,
+            [
+                (GMX_MARGIN, 100.0),
+                (GMX_FEES, -5.0),
+                (GMX_MARGIN, 30.0),
+                (GMX_PNL, -30.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn gmx_full_close_pays_out_remaining_collateral() {
+        let me = Address::repeat_byte(0x11);
+        let account = format!("{:#x}", me);
+        let amounts = decrease(
+            me,
+            165,
+            vec![
+                event(
+                    "UpdatePnl",
+                    &[("key", "0x01"), ("hasProfit", "false"), ("delta", "30")],
+                ),
+                event(
+                    "DecreasePosition",
+                    &[
+                        ("key", "0x01"),
+                        ("account", &account),
+                        ("collateralDelta", "100"),
+                        ("fee", "5"),
+                    ],
+                ),
+                event("ClosePosition", &[("key", "0x01"), ("collateral", "100")]),
+            ],
+        );
+        assert_eq!(
+            amounts,
+            [
+                (GMX_MARGIN, 170.0),
+                (GMX_FEES, -5.0),
+                (GMX_MARGIN, 30.0),
+                (GMX_PNL, -30.0)
+            ]
+        );
+    }
+}
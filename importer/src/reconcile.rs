@@ -0,0 +1,211 @@
+use csv::Writer;
+use ethers::types::Address;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::io;
+
+use crate::blockchain::{self, ChainState};
+use crate::export::Split;
+use crate::report;
+use crate::tokens::{self, MergeGroups};
+
+/// A commodity whose exported balance differs from the balance on chain
+#[derive(Debug)]
+pub struct Discrepancy {
+    pub commodity: String,
+    /// Net sum of the exported splits
+    pub exported: f64,
+    pub on_chain: f64,
+    /// Token contracts whose transfers were seen for this commodity
+    pub contracts: Vec<Address>,
+}
+
+impl Discrepancy {
+    pub fn difference(&self) -> f64 {
+        self.on_chain - self.exported
+    }
+}
+
+/// Group the token contracts seen in `txs` by commodity. Whitelisted contracts use
+/// their commodity, or its group if `merge` groups are given, and everything else the
+/// symbol reported by the explorer, so tokens dropped from the export still show
+/// up next to the commodity they claim to be.
+fn contributing_contracts(
+    txs: &[blockchain::Transaction],
+    merge: Option<&MergeGroups>,
+) -> BTreeMap<String, BTreeSet<Address>> {
+    let mut map: BTreeMap<String, BTreeSet<Address>> = BTreeMap::new();
+    for tr in txs.iter().flat_map(|tx| &tx.transfers) {
+        let symbol = tokens::GOOD_TOKENS
+            .get(&tr.token_contract)
+            .map(|info| merge.map_or(info.symbol, |m| m.commodity(info.symbol)))
+            .unwrap_or(tr.token_symbol.as_str());
+        map.entry(symbol.to_string())
+            .or_default()
+            .insert(tr.token_contract);
+    }
+    map
+}
+
+/// Compare the net sum of `splits` per commodity with the balance of `address`
+/// on chain at `block`, or the latest block if `None`, and return every
+/// commodity differing by more than `tolerance`. Commodities without a known
+/// contract to read, such as NFTs, have no on-chain source and are skipped.
+/// `merge` must hold the groups the splits were merged with by
+/// [`crate::export::merge_commodities`], if any.
+pub async fn reconcile<S>(
+    address: Address,
+    txs: &[blockchain::Transaction],
+    splits: &[Split],
+    state: &S,
+    block: Option<u64>,
+    tolerance: f64,
+    merge: Option<&MergeGroups>,
+) -> Result<Vec<Discrepancy>, Box<dyn Error>>
+where
+    S: ChainState + Sync,
+{
+    let mut exported: BTreeMap<&str, f64> = BTreeMap::new();
+    for split in splits {
+        *exported.entry(split.commodity.as_str()).or_default() += split.amount;
+    }
+    let mut contracts = contributing_contracts(txs, merge);
+
+    let mut res = Vec::new();
+    for (commodity, amount) in exported {
+        let Some(on_chain) = report::chain_balance(state, commodity, address, block, merge).await?
+        else {
+            continue;
+        };
+        if (on_chain - amount).abs() > tolerance {
+            res.push(Discrepancy {
+                commodity: commodity.to_string(),
+                exported: amount,
+                on_chain,
+                contracts: contracts
+                    .remove(commodity)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .collect(),
+            });
+        }
+    }
+    Ok(res)
+}
+
+/// Write discrepancies as CSV to `writer`
+pub fn write_discrepancies<W: io::Write>(
+    writer: W,
+    discrepancies: &[Discrepancy],
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = Writer::from_writer(writer);
+    wtr.write_record([
+        "Commodity",
+        "Exported",
+        "On-chain",
+        "Difference",
+        "Contracts",
+    ])?;
+    for d in discrepancies {
+        let contracts: Vec<String> = d.contracts.iter().map(|a| format!("{:#x}", a)).collect();
+        wtr.write_record([
+            d.commodity.clone(),
+            d.exported.to_string(),
+            d.on_chain.to_string(),
+            d.difference().to_string(),
+            contracts.join(" "),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::test_util::MockState;
+    use crate::blockchain::{Erc20Transfer, Transaction};
+    use chrono::NaiveDate;
+    use ethers::types::{H256, U256};
+
+    fn split(amount: f64) -> Split {
+        Split {
+            id: String::new(),
+            date: NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(),
+            description: String::new(),
+            account: "Trade".to_string(),
+            commodity: "ETH".to_string(),
+            amount,
+            memo: String::new(),
+            time: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn reconcile_reports_differences() {
+        let state = MockState::constant(U256::exp10(18));
+        let splits = vec![split(2.0), split(-0.5)];
+        let res = reconcile(Address::zero(), &[], &splits, &state, None, 1e-9, None)
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].exported, 1.5);
+        assert_eq!(res[0].difference(), -0.5);
+
+        let splits = vec![split(1.0)];
+        let res = reconcile(Address::zero(), &[], &splits, &state, None, 1e-9, None)
+            .await
+            .unwrap();
+        assert!(res.is_empty());
+
+        // NFTs and unknown tokens have no balance to compare with
+        let nft = Split {
+            commodity: "NFT PUNK".to_string(),
+            ..split(1.0)
+        };
+        let unknown = Split {
+            commodity: "SCAM".to_string(),
+            ..split(5.0)
+        };
+        let splits = vec![split(1.0), nft, unknown];
+        let res = reconcile(Address::zero(), &[], &splits, &state, None, 1e-9, None)
+            .await
+            .unwrap();
+        assert!(res.is_empty());
+    }
+
+    #[test]
+    fn contracts_grouped_by_commodity() {
+        let transfer = |contract: Address, symbol: &str| Erc20Transfer {
+            token_contract: contract,
+            from: Address::zero(),
+            to: None,
+            value: U256::one(),
+            token_name: symbol.to_string(),
+            token_symbol: symbol.to_string(),
+            token_decimal: "6".to_string(),
+            counterparty: None,
+        };
+        let usdc: Address = "0xff970a61a04b1ca14834a43f5de4533ebddb5cc8"
+            .parse()
+            .unwrap();
+        let fake = Address::repeat_byte(0x42);
+        let tx = Transaction {
+            hash: H256::zero(),
+            block_number: 0,
+            timestamp: 0,
+            from: Address::zero(),
+            to: None,
+            value: U256::zero(),
+            category: None,
+            description: None,
+            transfers: vec![transfer(usdc, "USDC.e"), transfer(fake, "USDC")],
+            ..Default::default()
+        };
+        let map = contributing_contracts(std::slice::from_ref(&tx), None);
+        assert_eq!(map["USDC"].len(), 1);
+        assert_eq!(map["USDC.e"].len(), 1);
+        let map = contributing_contracts(&[tx], Some(&MergeGroups::builtin()));
+        assert_eq!(map["USDC"].len(), 2);
+    }
+}
@@ -0,0 +1,569 @@
+use chrono::{Datelike, Months, NaiveDate};
+use csv::Writer;
+use ethers::types::{Address, U256};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use crate::blockchain::{self, ChainState};
+use crate::export::{value_to_f64, Split};
+use crate::prices::PriceTable;
+use crate::tokens::{self, MergeGroups};
+
+/// Holding period classification of a disposal
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Term {
+    Short,
+    Long,
+}
+
+/// A single disposal line of a tax report, matched against one acquisition lot
+#[derive(Debug)]
+pub struct Disposal {
+    pub commodity: String,
+    pub amount: f64,
+    /// Acquisition date of the matched lot, `None` if no lot was available
+    pub acquired: Option<NaiveDate>,
+    pub disposed: NaiveDate,
+    pub proceeds: f64,
+    pub cost_basis: f64,
+    pub term: Term,
+}
+
+impl Disposal {
+    pub fn gain(&self) -> f64 {
+        self.proceeds - self.cost_basis
+    }
+}
+
+/// An open acquisition lot of a commodity
+#[derive(Debug)]
+struct Lot {
+    date: NaiveDate,
+    amount: f64,
+    unit_cost: f64,
+}
+
+fn term(acquired: Option<NaiveDate>, disposed: NaiveDate) -> Term {
+    match acquired.and_then(|d| d.checked_add_months(Months::new(12))) {
+        Some(year_later) if disposed > year_later => Term::Long,
+        _ => Term::Short,
+    }
+}
+
+/// Remove `amount` from the front of `queue`, returning the acquisition date,
+/// amount and unit cost of every matched piece. Amounts not covered by a lot are
+/// returned without an acquisition date and with zero cost.
+fn take_lots(queue: &mut VecDeque<Lot>, amount: f64) -> Vec<(Option<NaiveDate>, f64, f64)> {
+    let mut res = Vec::new();
+    let mut remaining = amount;
+    while remaining > 0.0 {
+        let piece = match queue.front_mut() {
+            Some(lot) => {
+                let amount = remaining.min(lot.amount);
+                lot.amount -= amount;
+                let matched = (Some(lot.date), amount, lot.unit_cost);
+                if lot.amount <= 0.0 {
+                    queue.pop_front();
+                }
+                matched
+            }
+            None => (None, remaining, 0.0),
+        };
+        remaining -= piece.1;
+        res.push(piece);
+    }
+    res
+}
+
+/// Splits booked against another `Assets:` account move value between accounts
+/// of the same owner and are neither acquisitions nor disposals.
+fn is_internal(split: &Split) -> bool {
+    split.account.starts_with("Assets:")
+}
+
+/// Match every disposal in `splits` against earlier acquisitions using FIFO lots
+/// and return the disposals that happened in `year`. Positive split amounts are
+/// acquisitions and negative amounts are disposals. Liability tokens are not
+/// property and are skipped, as are internal transfers between asset accounts.
+/// A transaction converting one commodity into another purely between asset
+/// accounts, such as wrapping ETH, carries the lots over to the new commodity
+/// without realizing a gain. Commodities without a known price on a given date
+/// are valued at zero.
+pub fn tax_report(splits: &[Split], prices: &PriceTable, year: i32) -> Vec<Disposal> {
+    // Group the splits of each transaction even when they are not adjacent,
+    // keeping the input order within a day.
+    let mut index: HashMap<(NaiveDate, &str), usize> = HashMap::new();
+    let mut groups: Vec<Vec<&Split>> = Vec::new();
+    for split in splits
+        .iter()
+        .filter(|s| !tokens::is_liability_symbol(&s.commodity))
+    {
+        let i = *index.entry((split.date, &split.id)).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[i].push(split);
+    }
+    groups.sort_by_key(|g| g[0].date);
+
+    let mut lots: HashMap<&str, VecDeque<Lot>> = HashMap::new();
+    let mut res = Vec::new();
+    for group in &groups {
+        if group.iter().all(|s| is_internal(s)) {
+            if let [from, to] = group.as_slice() {
+                let (from, to) = if from.amount < 0.0 {
+                    (from, to)
+                } else {
+                    (to, from)
+                };
+                if from.amount < 0.0 && to.amount > 0.0 && from.commodity != to.commodity {
+                    let ratio = to.amount / -from.amount;
+                    let source = lots.entry(from.commodity.as_str()).or_default();
+                    let moved = take_lots(source, -from.amount);
+                    let target = lots.entry(to.commodity.as_str()).or_default();
+                    for (acquired, amount, unit_cost) in moved {
+                        target.push_back(Lot {
+                            date: acquired.unwrap_or(to.date),
+                            amount: amount * ratio,
+                            unit_cost: unit_cost / ratio,
+                        });
+                    }
+                }
+            }
+            continue;
+        }
+
+        for split in group.iter().filter(|s| !is_internal(s)) {
+            let price = prices.price(&split.commodity, split.date).unwrap_or(0.0);
+            let queue = lots.entry(split.commodity.as_str()).or_default();
+            if split.amount > 0.0 {
+                queue.push_back(Lot {
+                    date: split.date,
+                    amount: split.amount,
+                    unit_cost: price,
+                });
+                continue;
+            }
+            if split.date.year() != year {
+                take_lots(queue, -split.amount);
+                continue;
+            }
+            for (acquired, amount, unit_cost) in take_lots(queue, -split.amount) {
+                res.push(Disposal {
+                    commodity: split.commodity.clone(),
+                    amount,
+                    acquired,
+                    disposed: split.date,
+                    proceeds: amount * price,
+                    cost_basis: amount * unit_cost,
+                    term: term(acquired, split.date),
+                });
+            }
+        }
+    }
+    res
+}
+
+/// Write disposals to `path` as CSV using the Form 8949 column layout
+pub fn write_tax_csv(path: &Path, disposals: &[Disposal]) -> Result<(), Box<dyn Error>> {
+    let file = File::create(path)?;
+    let mut wtr = Writer::from_writer(file);
+    wtr.write_record([
+        "Description of property",
+        "Date acquired",
+        "Date sold or disposed of",
+        "Proceeds",
+        "Cost or other basis",
+        "Code",
+        "Amount of adjustment",
+        "Gain or (loss)",
+        "Term",
+    ])?;
+    for d in disposals {
+        let description = match d.acquired {
+            Some(_) => format!("{} {}", d.amount, d.commodity),
+            None => format!("{} {} (unknown basis)", d.amount, d.commodity),
+        };
+        wtr.write_record([
+            description,
+            d.acquired.map(|a| a.to_string()).unwrap_or_default(),
+            d.disposed.to_string(),
+            format!("{:.2}", d.proceeds),
+            format!("{:.2}", d.cost_basis),
+            String::new(),
+            String::new(),
+            format!("{:.2}", d.gain()),
+            match d.term {
+                Term::Short => "Short-term".to_string(),
+                Term::Long => "Long-term".to_string(),
+            },
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Balance of a single commodity at a point in time
+#[derive(Debug)]
+pub struct Balance {
+    pub commodity: String,
+    pub amount: f64,
+    /// Fiat value of `amount`, if a price is known
+    pub value: Option<f64>,
+    /// Balance read from the chain, if it was cross-checked and the commodity
+    /// has a known contract
+    pub on_chain: Option<f64>,
+}
+
+/// Replay `splits` up to and including `date` and return the resulting balance of
+/// every commodity.
+pub fn balance_report(splits: &[Split], prices: &PriceTable, date: NaiveDate) -> Vec<Balance> {
+    let mut totals: BTreeMap<&str, f64> = BTreeMap::new();
+    for split in splits.iter().filter(|s| s.date <= date) {
+        *totals.entry(split.commodity.as_str()).or_default() += split.amount;
+    }
+    totals
+        .into_iter()
+        .map(|(commodity, amount)| Balance {
+            commodity: commodity.to_string(),
+            amount,
+            value: prices.price(commodity, date).map(|p| p * amount),
+            on_chain: None,
+        })
+        .collect()
+}
+
+/// Read the balance of `commodity` held by `owner` at `block`. Native ETH is read
+/// with `eth_getBalance` while tokens sum `balanceOf` over every whitelisted
+/// contract booked as the commodity, with the `merge` groups resolved if given. Liability balances are negative, matching the sign they are exported
+/// with. Returns `None` for commodities without a known contract, such as NFTs
+/// and tokens outside the whitelist.
+pub async fn chain_balance<S>(
+    state: &S,
+    commodity: &str,
+    owner: Address,
+    block: Option<u64>,
+    merge: Option<&MergeGroups>,
+) -> Result<Option<f64>, Box<dyn Error>>
+where
+    S: ChainState + Sync,
+{
+    if commodity == "ETH" {
+        let wei = state.get_balance(owner, block).await?;
+        return Ok(Some(value_to_f64(wei, 18)));
+    }
+    let contracts = tokens::contracts_for(commodity, merge);
+    if contracts.is_empty() {
+        return Ok(None);
+    }
+    let mut total = 0.0;
+    for token in contracts {
+        let raw = blockchain::token_balance(state, token, owner, block).await?;
+        let decimals = blockchain::token_decimals(state, token).await?;
+        total += value_to_f64(raw, decimals);
+    }
+    if tokens::is_liability_symbol(commodity) {
+        total = -total;
+    }
+    Ok(Some(total))
+}
+
+/// Fill in [`Balance::on_chain`] for every balance using [`chain_balance`],
+/// leaving commodities without a known contract unchecked.
+pub async fn cross_check<S>(
+    balances: &mut [Balance],
+    state: &S,
+    owner: Address,
+    block: Option<u64>,
+    merge: Option<&MergeGroups>,
+) -> Result<(), Box<dyn Error>>
+where
+    S: ChainState + Sync,
+{
+    for bal in balances.iter_mut() {
+        bal.on_chain = chain_balance(state, &bal.commodity, owner, block, merge).await?;
+    }
+    Ok(())
+}
+
+/// Write balances as CSV to `writer`
+pub fn write_balances<W: io::Write>(writer: W, balances: &[Balance]) -> Result<(), Box<dyn Error>> {
+    let mut wtr = Writer::from_writer(writer);
+    wtr.write_record(["Commodity", "Balance", "Value", "On-chain", "Difference"])?;
+    for bal in balances {
+        wtr.write_record([
+            bal.commodity.clone(),
+            bal.amount.to_string(),
+            bal.value.map(|v| format!("{:.2}", v)).unwrap_or_default(),
+            bal.on_chain.map(|v| v.to_string()).unwrap_or_default(),
+            bal.on_chain
+                .map(|v| (v - bal.amount).to_string())
+                .unwrap_or_default(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Number of tokens of a single NFT held by the wallet
+#[derive(Debug, PartialEq)]
+pub struct NftHolding {
+    pub commodity: String,
+    pub contract: Address,
+    pub token_id: String,
+    pub amount: U256,
+}
+
+/// Replay the NFT transfers in `txs` and return every token `address` still
+/// holds, ordered by contract and token ID.
+pub fn nft_holdings(address: Address, txs: &[blockchain::Transaction]) -> Vec<NftHolding> {
+    let mut held: BTreeMap<(Address, &str), (String, U256)> = BTreeMap::new();
+    for nft in txs.iter().flat_map(|tx| &tx.nft_transfers) {
+        let entry = held
+            .entry((nft.contract, nft.token_id.as_str()))
+            .or_insert_with(|| (nft.commodity(), U256::zero()));
+        if nft.to == Some(address) {
+            entry.1 = entry.1.saturating_add(nft.amount);
+        }
+        if nft.from == address {
+            entry.1 = entry.1.saturating_sub(nft.amount);
+        }
+    }
+    held.into_iter()
+        .filter(|(_, (_, amount))| !amount.is_zero())
+        .map(|((contract, token_id), (commodity, amount))| NftHolding {
+            commodity,
+            contract,
+            token_id: token_id.to_string(),
+            amount,
+        })
+        .collect()
+}
+
+/// Write NFT holdings as CSV to `writer`
+pub fn write_nft_holdings<W: io::Write>(
+    writer: W,
+    holdings: &[NftHolding],
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = Writer::from_writer(writer);
+    wtr.write_record(["Commodity", "Contract", "Token ID", "Amount"])?;
+    for h in holdings {
+        wtr.write_record([
+            h.commodity.clone(),
+            format!("{:#x}", h.contract),
+            h.token_id.clone(),
+            h.amount.to_string(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::test_util::MockState;
+    use crate::blockchain::{NftStandard, NftTransfer};
+    use std::env;
+    use std::fs;
+
+    fn split(date: NaiveDate, amount: f64) -> Split {
+        Split {
+            id: String::new(),
+            date,
+            description: String::new(),
+            account: "Trade".to_string(),
+            commodity: "ETH".to_string(),
+            amount,
+            memo: String::new(),
+            time: None,
+        }
+    }
+
+    fn day(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn tax_report_matches_fifo_lots() {
+        let mut prices = PriceTable::default();
+        prices.insert("ETH", day(2022, 1, 1), 1000.0);
+        prices.insert("ETH", day(2023, 1, 1), 2000.0);
+        prices.insert("ETH", day(2023, 6, 1), 3000.0);
+
+        let splits = vec![
+            split(day(2022, 1, 1), 1.0),
+            split(day(2023, 1, 1), 1.0),
+            split(day(2023, 6, 1), -1.5),
+        ];
+        let res = tax_report(&splits, &prices, 2023);
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].acquired, Some(day(2022, 1, 1)));
+        assert_eq!(res[0].term, Term::Long);
+        assert_eq!(res[0].proceeds, 3000.0);
+        assert_eq!(res[0].gain(), 2000.0);
+        assert_eq!(res[1].acquired, Some(day(2023, 1, 1)));
+        assert_eq!(res[1].term, Term::Short);
+        assert_eq!(res[1].amount, 0.5);
+        assert_eq!(res[1].cost_basis, 1000.0);
+    }
+
+    #[test]
+    fn tax_report_filters_year_and_handles_missing_lots() {
+        let mut prices = PriceTable::default();
+        prices.insert("ETH", day(2022, 1, 1), 1000.0);
+
+        let splits = vec![split(day(2022, 3, 1), -1.0), split(day(2023, 3, 1), -2.0)];
+        let res = tax_report(&splits, &prices, 2023);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].acquired, None);
+        assert_eq!(res[0].cost_basis, 0.0);
+        assert_eq!(res[0].proceeds, 2000.0);
+
+        let path = env::temp_dir().join("tax_report_test.csv");
+        write_tax_csv(&path, &res).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(path);
+        let line = contents.lines().nth(1).unwrap();
+        assert!(line.starts_with("2 ETH (unknown basis),,2023-03-01,"));
+    }
+
+    #[test]
+    fn conversions_carry_lots_over() {
+        let mut prices = PriceTable::default();
+        prices.insert("ETH", day(2022, 1, 1), 1000.0);
+        prices.insert("WETH", day(2023, 6, 1), 3000.0);
+
+        let wrap = |amount: f64, commodity: &str, account: &str| Split {
+            id: "wrap".to_string(),
+            date: day(2022, 6, 1),
+            description: String::new(),
+            account: account.to_string(),
+            commodity: commodity.to_string(),
+            amount,
+            memo: String::new(),
+            time: None,
+        };
+        let mut sell = split(day(2023, 6, 1), -1.0);
+        sell.commodity = "WETH".to_string();
+        let splits = vec![
+            split(day(2022, 1, 1), 1.0),
+            wrap(-1.0, "ETH", "Assets:Wallet:ETH"),
+            wrap(1.0, "WETH", "Assets:Wallet:WETH"),
+            sell,
+        ];
+        let res = tax_report(&splits, &prices, 2022);
+        assert!(res.is_empty());
+        let res = tax_report(&splits, &prices, 2023);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].acquired, Some(day(2022, 1, 1)));
+        assert_eq!(res[0].cost_basis, 1000.0);
+        assert_eq!(res[0].term, Term::Long);
+    }
+
+    #[test]
+    fn interleaved_splits_stay_in_one_transaction() {
+        let mut prices = PriceTable::default();
+        prices.insert("ETH", day(2022, 1, 1), 1000.0);
+
+        let leg = |id: &str, amount: f64, commodity: &str, account: &str| Split {
+            id: id.to_string(),
+            date: day(2022, 6, 1),
+            description: String::new(),
+            account: account.to_string(),
+            commodity: commodity.to_string(),
+            amount,
+            memo: String::new(),
+            time: None,
+        };
+        let splits = vec![
+            split(day(2022, 1, 1), 2.0),
+            leg("wrap", -1.0, "ETH", "Assets:Wallet:ETH"),
+            leg("fee", -0.1, "ETH", "Expenses:Fees:L2 Execution"),
+            leg("wrap", 1.0, "WETH", "Assets:Wallet:WETH"),
+            Split {
+                commodity: "WETH".to_string(),
+                ..split(day(2023, 6, 1), -1.0)
+            },
+        ];
+        let res = tax_report(&splits, &prices, 2022);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].amount, 0.1);
+        let res = tax_report(&splits, &prices, 2023);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].acquired, Some(day(2022, 1, 1)));
+        assert_eq!(res[0].cost_basis, 1000.0);
+    }
+
+    #[test]
+    fn balance_report_replays_until_date() {
+        let mut prices = PriceTable::default();
+        prices.insert("ETH", day(2023, 1, 1), 2000.0);
+        let splits = vec![
+            split(day(2023, 1, 1), 2.0),
+            split(day(2023, 2, 1), -0.5),
+            split(day(2024, 1, 1), -1.0),
+        ];
+        let res = balance_report(&splits, &prices, day(2023, 12, 31));
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].amount, 1.5);
+        assert_eq!(res[0].value, Some(3000.0));
+    }
+
+    #[tokio::test]
+    async fn cross_check_reads_native_balance() {
+        let balance = |commodity: &str| Balance {
+            commodity: commodity.to_string(),
+            amount: 1.0,
+            value: None,
+            on_chain: None,
+        };
+        let mut balances = vec![balance("ETH"), balance("NFT PUNK")];
+        let state = MockState::new(|_, block| U256::exp10(18) * block.unwrap_or_default());
+        cross_check(&mut balances, &state, Address::zero(), Some(3), None)
+            .await
+            .unwrap();
+        assert_eq!(balances[0].on_chain, Some(3.0));
+        assert_eq!(balances[1].on_chain, None);
+
+        let mut out = Vec::new();
+        write_balances(&mut out, &balances).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("ETH,1,,3,2"));
+        assert!(out.contains("NFT PUNK,1,,,"));
+    }
+
+    #[test]
+    fn nft_holdings_drop_sold_tokens() {
+        let me = Address::repeat_byte(0x11);
+        let other = Address::repeat_byte(0x22);
+        let nft = |from: Address, to: Address, id: &str| NftTransfer {
+            standard: NftStandard::Erc721,
+            contract: Address::repeat_byte(0x77),
+            from,
+            to: Some(to),
+            token_id: id.to_string(),
+            amount: U256::one(),
+            collection_name: String::new(),
+            collection_symbol: "PUNK".to_string(),
+        };
+        let txs = vec![blockchain::Transaction {
+            nft_transfers: vec![
+                nft(other, me, "1"),
+                nft(other, me, "2"),
+                nft(me, other, "1"),
+            ],
+            ..Default::default()
+        }];
+        let res = nft_holdings(me, &txs);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].token_id, "2");
+
+        let mut out = Vec::new();
+        write_nft_holdings(&mut out, &res).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("NFT PUNK,"));
+    }
+}
@@ -0,0 +1,298 @@
+use chrono::{DateTime, NaiveDate};
+use ethers::types::{Address, Bytes};
+use serde::Deserialize;
+use std::error::Error;
+use std::path::Path;
+
+use crate::blockchain::{self, Transaction};
+use crate::export::value_to_f64;
+
+/// Direction of a transaction relative to the tracked address
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// Conditions a transaction must satisfy for a rule to apply. Unset conditions
+/// match every transaction.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RuleMatch {
+    /// Function selector such as `0xa9059cbb`
+    pub method_id: Option<Bytes>,
+    /// Function name, compared case-insensitively without the argument list
+    /// against the explorer's, the ABI-decoded and the guessed function name
+    pub function_name: Option<String>,
+    /// Token contract that must be transferred
+    pub token: Option<Address>,
+    pub direction: Option<Direction>,
+    /// Smallest matching amount, in ETH or in units of `token` when set
+    pub min_amount: Option<f64>,
+    /// Largest matching amount, in ETH or in units of `token` when set
+    pub max_amount: Option<f64>,
+    /// First matching date (inclusive)
+    pub after: Option<NaiveDate>,
+    /// Last matching date (inclusive)
+    pub before: Option<NaiveDate>,
+    /// Address on the other side of the transaction
+    pub counterparty: Option<Address>,
+    /// Tag name of the counterparty, compared case-insensitively
+    pub counterparty_tag: Option<String>,
+}
+
+/// A single categorization rule
+#[derive(Clone, Debug, Deserialize)]
+pub struct Rule {
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Rules with a higher priority win when using [`Mode::Priority`]
+    #[serde(default)]
+    pub priority: i64,
+    #[serde(default)]
+    pub when: RuleMatch,
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub account: Option<String>,
+}
+
+/// How the rule to apply is chosen when several rules match
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// The first matching rule in file order wins
+    #[default]
+    First,
+    /// The matching rule with the highest priority wins, ties going to the
+    /// earlier rule
+    Priority,
+}
+
+/// Ordered set of categorization rules
+#[derive(Debug, Default, Deserialize)]
+pub struct Rules {
+    #[serde(default)]
+    pub mode: Mode,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+fn in_range(amount: f64, min: Option<f64>, max: Option<f64>) -> bool {
+    min.is_none_or(|m| amount >= m) && max.is_none_or(|m| amount <= m)
+}
+
+fn base_name(signature: &str) -> &str {
+    signature.split('(').next().unwrap_or(signature).trim()
+}
+
+impl RuleMatch {
+    /// Return true if `tx` satisfies every condition from the point of view of
+    /// `address`.
+    pub fn matches(&self, address: Address, tx: &Transaction) -> bool {
+        let outgoing = tx.from == address;
+        let counterparty = if outgoing { tx.to } else { Some(tx.from) };
+
+        if self.method_id.is_some() && self.method_id != tx.method_id {
+            return false;
+        }
+        if let Some(name) = &self.function_name {
+            let wanted = base_name(name);
+            let explorer = tx.function_name.as_deref().map(base_name);
+            let decoded = tx.decoded.as_ref().map(|c| c.name.as_str());
+            let guessed = tx.guessed_method.as_deref();
+            if ![explorer, decoded, guessed]
+                .into_iter()
+                .flatten()
+                .any(|f| f.eq_ignore_ascii_case(wanted))
+            {
+                return false;
+            }
+        }
+        if self.counterparty.is_some() && self.counterparty != counterparty {
+            return false;
+        }
+        if let Some(tag) = &self.counterparty_tag {
+            match tx.counterparty.as_deref() {
+                Some(cp) if cp.eq_ignore_ascii_case(tag) => {}
+                _ => return false,
+            }
+        }
+        if self.after.is_some() || self.before.is_some() {
+            let date = DateTime::from_timestamp(tx.timestamp as i64, 0)
+                .unwrap_or_default()
+                .date_naive();
+            if self.after.is_some_and(|d| date < d) || self.before.is_some_and(|d| date > d) {
+                return false;
+            }
+        }
+
+        match self.token {
+            Some(token) => tx.transfers.iter().any(|tr| {
+                let direction = if tr.from == address {
+                    Direction::Out
+                } else {
+                    Direction::In
+                };
+                let decimals = tr.token_decimal.parse::<u32>().unwrap_or(18);
+                tr.token_contract == token
+                    && self.direction.is_none_or(|d| d == direction)
+                    && in_range(
+                        value_to_f64(tr.value, decimals),
+                        self.min_amount,
+                        self.max_amount,
+                    )
+            }),
+            None => {
+                let direction = if outgoing {
+                    Direction::Out
+                } else {
+                    Direction::In
+                };
+                self.direction.is_none_or(|d| d == direction)
+                    && in_range(value_to_f64(tx.value, 18), self.min_amount, self.max_amount)
+            }
+        }
+    }
+}
+
+impl Rules {
+    /// Load rules from the given file path. The format is inferred from the
+    /// extension and may be TOML, JSON, or YAML.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        blockchain::load_structured(path)
+    }
+
+    /// Return the rule to apply to `tx`, if any.
+    pub fn find(&self, address: Address, tx: &Transaction) -> Option<&Rule> {
+        let mut matching = self.rules.iter().filter(|r| r.when.matches(address, tx));
+        match self.mode {
+            Mode::First => matching.next(),
+            Mode::Priority => matching.rev().max_by_key(|r| r.priority),
+        }
+    }
+}
+
+/// Assign categories, descriptions and accounts from the matching rule of each
+/// transaction. Values left unset by the rule are kept.
+pub fn apply_rules(address: Address, txs: &mut [Transaction], rules: &Rules) {
+    for tx in txs {
+        if let Some(rule) = rules.find(address, tx) {
+            if rule.category.is_some() {
+                tx.category = rule.category.clone();
+            }
+            if rule.description.is_some() {
+                tx.description = rule.description.clone();
+            }
+            if rule.account.is_some() {
+                tx.account = rule.account.clone();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Erc20Transfer;
+    use ethers::types::U256;
+
+    fn rules(src: &str) -> Rules {
+        toml::from_str(src).unwrap()
+    }
+
+    fn transfer_tx(address: Address, amount: u64) -> Transaction {
+        Transaction {
+            from: address,
+            to: Some(Address::repeat_byte(0x22)),
+            method_id: Some(Bytes::from(vec![0xa9, 0x05, 0x9c, 0xbb])),
+            function_name: Some("transfer(address to, uint256 amount)".to_string()),
+            timestamp: 1_700_000_000,
+            transfers: vec![Erc20Transfer {
+                token_contract: Address::repeat_byte(0x33),
+                from: address,
+                to: Some(Address::repeat_byte(0x22)),
+                value: U256::from(amount),
+                token_name: "T".to_string(),
+                token_symbol: "T".to_string(),
+                token_decimal: "0".to_string(),
+                counterparty: None,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let address = Address::repeat_byte(0x11);
+        let rules = rules(
+            r#"
+            [[rules]]
+            category = "Large"
+            when = { token = "0x3333333333333333333333333333333333333333", min_amount = 100 }
+
+            [[rules]]
+            category = "Payment"
+            account = "Expenses:Payments"
+            when = { function_name = "Transfer", direction = "out", after = "2023-01-01" }
+
+            [[rules]]
+            category = "Never"
+            "#,
+        );
+        let mut txs = vec![transfer_tx(address, 5), transfer_tx(address, 500)];
+        apply_rules(address, &mut txs, &rules);
+        assert_eq!(txs[0].category.as_deref(), Some("Payment"));
+        assert_eq!(txs[0].account.as_deref(), Some("Expenses:Payments"));
+        assert_eq!(txs[1].category.as_deref(), Some("Large"));
+        assert_eq!(txs[1].account, None);
+    }
+
+    #[test]
+    fn priority_mode_picks_highest_priority() {
+        let address = Address::repeat_byte(0x11);
+        let rules = rules(
+            r#"
+            mode = "priority"
+
+            [[rules]]
+            category = "Low"
+            when = { method_id = "0xa9059cbb" }
+
+            [[rules]]
+            category = "High"
+            priority = 10
+            when = { counterparty = "0x2222222222222222222222222222222222222222" }
+
+            [[rules]]
+            category = "Incoming"
+            priority = 20
+            when = { direction = "in" }
+            "#,
+        );
+        let mut txs = vec![transfer_tx(address, 1)];
+        apply_rules(address, &mut txs, &rules);
+        assert_eq!(txs[0].category.as_deref(), Some("High"));
+    }
+
+    #[test]
+    fn counterparty_tag_matches_name() {
+        let address = Address::repeat_byte(0x11);
+        let rules = rules(
+            r#"
+            [[rules]]
+            category = "Rent"
+            when = { counterparty_tag = "alice" }
+            "#,
+        );
+        let mut tagged = transfer_tx(address, 1);
+        tagged.counterparty = Some("Alice".to_string());
+        let mut txs = vec![tagged, transfer_tx(address, 1)];
+        apply_rules(address, &mut txs, &rules);
+        assert_eq!(txs[0].category.as_deref(), Some("Rent"));
+        assert_eq!(txs[1].category, None);
+    }
+}
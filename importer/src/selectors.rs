@@ -0,0 +1,173 @@
+use ethers::types::H256;
+use ethers::utils::{hex, id};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::blockchain::Transaction;
+
+/// Function signatures of common token, DEX, lending and bridge contracts on
+/// Arbitrum
+const BUNDLED: &[&str] = &[
+    "transfer(address,uint256)",
+    "transferFrom(address,address,uint256)",
+    "approve(address,uint256)",
+    "deposit()",
+    "withdraw(uint256)",
+    "multicall(bytes[])",
+    "multicall(uint256,bytes[])",
+    "execute(bytes,bytes[])",
+    "execute(bytes,bytes[],uint256)",
+    "exactInputSingle((address,address,uint24,address,uint256,uint256,uint160))",
+    "exactInputSingle((address,address,uint24,address,uint256,uint256,uint256,uint160))",
+    "exactInput((bytes,address,uint256,uint256))",
+    "exactOutputSingle((address,address,uint24,address,uint256,uint256,uint160))",
+    "swapExactTokensForTokens(uint256,uint256,address[],address,uint256)",
+    "swapExactETHForTokens(uint256,address[],address,uint256)",
+    "swapExactTokensForETH(uint256,uint256,address[],address,uint256)",
+    "mint((address,address,uint24,int24,int24,uint256,uint256,uint256,uint256,address,uint256))",
+    "increaseLiquidity((uint256,uint256,uint256,uint256,uint256))",
+    "decreaseLiquidity((uint256,uint128,uint256,uint256,uint256))",
+    "collect((uint256,address,uint128,uint128))",
+    "supply(address,uint256,address,uint16)",
+    "withdraw(address,uint256,address)",
+    "borrow(address,uint256,uint256,uint16,address)",
+    "repay(address,uint256,uint256,address)",
+    "depositETH(address,address,uint16)",
+    "withdrawETH(address,uint256,address)",
+    "createIncreasePosition(address[],address,uint256,uint256,uint256,bool,uint256,uint256,bytes32,address)",
+    "createIncreasePositionETH(address[],address,uint256,uint256,bool,uint256,uint256,bytes32,address)",
+    "createDecreasePosition(address[],address,uint256,uint256,bool,address,uint256,uint256,uint256,bool,address)",
+    "outboundTransfer(address,address,uint256,bytes)",
+    "withdrawEth(address)",
+    "claim()",
+    "setApprovalForAll(address,bool)",
+    "safeTransferFrom(address,address,uint256)",
+    "safeTransferFrom(address,address,uint256,uint256,bytes)",
+];
+
+/// Offline database of function selectors and the signatures sharing them
+#[derive(Debug, Default)]
+pub struct SelectorDb(HashMap<[u8; 4], Vec<String>>);
+
+impl SelectorDb {
+    /// Return a database holding the bundled signatures.
+    pub fn bundled() -> Self {
+        let mut db = Self::default();
+        for sig in BUNDLED {
+            db.insert(id(sig), sig);
+        }
+        db
+    }
+
+    /// Add `signature` under `selector`, ignoring duplicates.
+    pub fn insert(&mut self, selector: [u8; 4], signature: &str) {
+        let entry = self.0.entry(selector).or_default();
+        if !entry.iter().any(|s| s == signature) {
+            entry.push(signature.to_string());
+        }
+    }
+
+    /// Add the signatures listed in `path`, one per line. A line is either a bare
+    /// signature whose selector is computed, or a `0x`-prefixed selector followed
+    /// by its signature. Empty lines and lines starting with `#` are skipped.
+    pub fn extend_from_file(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(char::is_whitespace) {
+                Some((selector, sig)) if selector.starts_with("0x") => {
+                    let bytes = hex::decode(selector)?;
+                    let selector: [u8; 4] = bytes
+                        .as_slice()
+                        .try_into()
+                        .map_err(|_| format!("invalid selector: {}", selector))?;
+                    self.insert(selector, sig.trim());
+                }
+                _ => self.insert(id(line), line),
+            }
+        }
+        Ok(())
+    }
+
+    /// Return every known signature sharing the first four bytes of `input`.
+    pub fn lookup(&self, input: &[u8]) -> &[String] {
+        input
+            .get(..4)
+            .and_then(|s| <[u8; 4]>::try_from(s).ok())
+            .and_then(|s| self.0.get(&s))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+}
+
+/// Guess the method of every transaction without decoded calldata from the
+/// selector of its input. When several signatures share a selector the first is
+/// used and all of them are kept in [`Transaction::method_candidates`]. Returns
+/// the hashes of such ambiguous transactions.
+pub fn apply_selectors(txs: &mut [Transaction], db: &SelectorDb) -> Vec<H256> {
+    let mut ambiguous = Vec::new();
+    for tx in txs.iter_mut().filter(|tx| tx.decoded.is_none()) {
+        let candidates = db.lookup(&tx.input);
+        let Some(first) = candidates.first() else {
+            continue;
+        };
+        tx.guessed_method = first.split('(').next().map(str::to_string);
+        if candidates.len() > 1 {
+            tx.method_candidates = candidates.to_vec();
+            ambiguous.push(tx.hash);
+        }
+    }
+    ambiguous
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn bundled_selectors_guess_method() {
+        let db = SelectorDb::bundled();
+        let mut txs = vec![
+            Transaction {
+                input: vec![0xa9, 0x05, 0x9c, 0xbb, 0x00].into(),
+                ..Default::default()
+            },
+            Transaction {
+                input: vec![0xde, 0xad].into(),
+                ..Default::default()
+            },
+        ];
+        let ambiguous = apply_selectors(&mut txs, &db);
+        assert!(ambiguous.is_empty());
+        assert_eq!(txs[0].guessed_method.as_deref(), Some("transfer"));
+        assert_eq!(txs[1].guessed_method, None);
+    }
+
+    #[test]
+    fn shared_selectors_are_reported() {
+        let path = env::temp_dir().join("selectors_test.txt");
+        fs::write(
+            &path,
+            "# collisions\n0xa9059cbb transferAlias(address,uint256)\nburn(uint256)\n",
+        )
+        .unwrap();
+        let mut db = SelectorDb::bundled();
+        db.extend_from_file(&path).unwrap();
+        let _ = fs::remove_file(path);
+        assert_eq!(db.lookup(&id("burn(uint256)")), ["burn(uint256)"]);
+
+        let mut txs = vec![Transaction {
+            hash: H256::repeat_byte(1),
+            input: vec![0xa9, 0x05, 0x9c, 0xbb].into(),
+            ..Default::default()
+        }];
+        let ambiguous = apply_selectors(&mut txs, &db);
+        assert_eq!(ambiguous, vec![H256::repeat_byte(1)]);
+        assert_eq!(txs[0].method_candidates.len(), 2);
+    }
+}
@@ -0,0 +1,258 @@
+use csv::Writer;
+use ethers::types::{Address, H256};
+use ethers::utils::format_units;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+
+use crate::blockchain::{Erc20Transfer, Transaction};
+use crate::tokens;
+
+/// Heuristic that flagged a transfer as spam
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpamReason {
+    /// Zero-value transfer in a transaction sent by someone else
+    ZeroValue,
+    /// Token using the symbol of a whitelisted token at a different contract
+    Impersonation,
+    /// Counterparty whose address mimics one the wallet has sent funds to
+    Lookalike,
+}
+
+impl fmt::Display for SpamReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SpamReason::ZeroValue => "zero value",
+            SpamReason::Impersonation => "impersonated token",
+            SpamReason::Lookalike => "lookalike address",
+        })
+    }
+}
+
+/// A transfer removed from a transaction as spam
+#[derive(Clone, Debug)]
+pub struct SpamTransfer {
+    pub hash: H256,
+    pub transfer: Erc20Transfer,
+    pub reason: SpamReason,
+}
+
+/// Return true if `a` and `b` differ but share their first and last four hex
+/// digits, the part wallets usually display.
+fn looks_alike(a: &Address, b: &Address) -> bool {
+    a != b && a[..2] == b[..2] && a[18..] == b[18..]
+}
+
+fn is_impersonation(tr: &Erc20Transfer) -> bool {
+    !tokens::GOOD_TOKENS.contains_key(&tr.token_contract)
+        && tokens::GOOD_TOKENS
+            .values()
+            .flat_map(|info| [Some(info.symbol), info.group])
+            .flatten()
+            .any(|symbol| symbol.eq_ignore_ascii_case(tr.token_symbol.trim()))
+}
+
+/// Return true if `address` signed `tx`.
+fn signed_by(address: Address, tx: &Transaction) -> bool {
+    tx.from == address && !tx.transfer_only
+}
+
+/// Addresses `address` has sent ETH or tokens to in transactions it signed
+fn known_recipients<'a>(
+    address: Address,
+    txs: impl IntoIterator<Item = &'a Transaction>,
+) -> HashSet<Address> {
+    let mut res = HashSet::new();
+    for tx in txs.into_iter().filter(|tx| signed_by(address, tx)) {
+        if let Some(to) = tx.to.filter(|_| !tx.value.is_zero()) {
+            res.insert(to);
+        }
+        res.extend(
+            tx.transfers
+                .iter()
+                .filter(|tr| tr.from == address && !tr.value.is_zero())
+                .filter_map(|tr| tr.to),
+        );
+    }
+    res
+}
+
+fn classify(
+    address: Address,
+    tx: &Transaction,
+    tr: &Erc20Transfer,
+    known: &HashSet<Address>,
+) -> Option<SpamReason> {
+    if signed_by(address, tx) {
+        return None;
+    }
+    if tr.value.is_zero() {
+        return Some(SpamReason::ZeroValue);
+    }
+    if is_impersonation(tr) {
+        return Some(SpamReason::Impersonation);
+    }
+    let counterparty = if tr.from == address { tr.to? } else { tr.from };
+    if !known.contains(&counterparty) && known.iter().any(|k| looks_alike(k, &counterparty)) {
+        return Some(SpamReason::Lookalike);
+    }
+    None
+}
+
+/// Remove spam transfers from every transaction not signed by `address` and
+/// return them. Transfers in transactions sent by the wallet itself are kept.
+/// Recipients paid in `earlier`, such as the transactions already stored, count
+/// as known like those paid in `txs`.
+pub fn filter_spam(
+    address: Address,
+    txs: &mut [Transaction],
+    earlier: &[Transaction],
+) -> Vec<SpamTransfer> {
+    let known = known_recipients(address, earlier.iter().chain(txs.iter()));
+    let mut res = Vec::new();
+    for tx in txs.iter_mut() {
+        let transfers = std::mem::take(&mut tx.transfers);
+        for tr in transfers {
+            match classify(address, tx, &tr, &known) {
+                Some(reason) => res.push(SpamTransfer {
+                    hash: tx.hash,
+                    transfer: tr,
+                    reason,
+                }),
+                None => tx.transfers.push(tr),
+            }
+        }
+    }
+    res
+}
+
+/// Write the filtered spam transfers to `path` as CSV
+pub fn write_spam_report(path: &Path, spam: &[SpamTransfer]) -> Result<(), Box<dyn Error>> {
+    let file = File::create(path)?;
+    let mut wtr = Writer::from_writer(file);
+    wtr.write_record([
+        "Transaction ID",
+        "Token Contract",
+        "Token",
+        "From",
+        "To",
+        "Amount",
+        "Reason",
+    ])?;
+    for s in spam {
+        let tr = &s.transfer;
+        let decimals = tr.token_decimal.parse::<u32>().unwrap_or(18);
+        wtr.write_record([
+            format!("{:#x}", s.hash),
+            format!("{:#x}", tr.token_contract),
+            tr.token_symbol.clone(),
+            format!("{:#x}", tr.from),
+            tr.to.map(|a| format!("{:#x}", a)).unwrap_or_default(),
+            format_units(tr.value, decimals).unwrap_or_else(|_| "0".to_string()),
+            s.reason.to_string(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::test_util::{normal_tx, transfer_event, MockClient};
+    use crate::blockchain::{self, BlockRange};
+    use ethers::etherscan::account::ERC20TokenTransferEvent;
+    use ethers::types::BlockNumber;
+
+    const USDC: &str = "0xaf88d065e77c8cc2239327c5edb3a432268e5831";
+
+    fn transfer(
+        hash: u8,
+        token: Address,
+        symbol: &str,
+        from: Address,
+        to: Address,
+        value: u64,
+    ) -> ERC20TokenTransferEvent {
+        let mut event = transfer_event(H256::repeat_byte(hash), token, from, to, value.into());
+        event.block_number = BlockNumber::Number(u64::from(hash).into());
+        event.token_symbol = symbol.to_string();
+        event.token_decimal = "6".to_string();
+        event
+    }
+
+    #[tokio::test]
+    async fn spam_transfers_are_removed() {
+        let me = Address::repeat_byte(0x11);
+        let usdc: Address = USDC.parse().unwrap();
+        let friend = Address::repeat_byte(0x22);
+        let mut lookalike = friend;
+        lookalike.0[10] = 0x99;
+        let stranger = Address::repeat_byte(0x33);
+
+        // Only the first transaction is signed by the wallet; the others are
+        // poisoning, airdrops and a genuine payment sent by someone else.
+        let client = MockClient {
+            tx_pages: vec![vec![normal_tx(H256::repeat_byte(1), me)]],
+            event_pages: vec![vec![
+                transfer(1, usdc, "USDC", me, friend, 5),
+                transfer(2, usdc, "USDC", me, lookalike, 0),
+                transfer(3, Address::repeat_byte(0x44), "usdc", stranger, me, 5),
+                transfer(4, usdc, "USDC", lookalike, me, 1),
+                transfer(5, usdc, "USDC", friend, me, 5),
+            ]],
+            ..MockClient::with_block_time(1)
+        };
+        let mut txs = blockchain::fetch_transactions_range(&client, me, BlockRange::default())
+            .await
+            .unwrap();
+        assert_eq!(txs.len(), 5);
+
+        let spam = filter_spam(me, &mut txs, &[]);
+        let reasons: Vec<SpamReason> = spam.iter().map(|s| s.reason).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                SpamReason::ZeroValue,
+                SpamReason::Impersonation,
+                SpamReason::Lookalike
+            ]
+        );
+        let kept: Vec<usize> = txs.iter().map(|tx| tx.transfers.len()).collect();
+        assert_eq!(kept, [1, 0, 0, 0, 1]);
+        assert_eq!(txs[4].transfers[0].from, friend);
+    }
+
+    #[tokio::test]
+    async fn lookalikes_of_earlier_recipients_are_removed() {
+        let me = Address::repeat_byte(0x11);
+        let usdc: Address = USDC.parse().unwrap();
+        let friend = Address::repeat_byte(0x22);
+        let mut lookalike = friend;
+        lookalike.0[10] = 0x99;
+
+        let earlier = MockClient {
+            tx_pages: vec![vec![normal_tx(H256::repeat_byte(1), me)]],
+            event_pages: vec![vec![transfer(1, usdc, "USDC", me, friend, 5)]],
+            ..MockClient::with_block_time(1)
+        };
+        let latest = MockClient {
+            event_pages: vec![vec![transfer(2, usdc, "USDC", lookalike, me, 1)]],
+            ..MockClient::with_block_time(1)
+        };
+        let range = BlockRange::default();
+        let earlier = blockchain::fetch_transactions_range(&earlier, me, range)
+            .await
+            .unwrap();
+        let mut txs = blockchain::fetch_transactions_range(&latest, me, range)
+            .await
+            .unwrap();
+
+        assert!(filter_spam(me, &mut txs.clone(), &[]).is_empty());
+        let spam = filter_spam(me, &mut txs, &earlier);
+        assert_eq!(spam.len(), 1);
+        assert_eq!(spam[0].reason, SpamReason::Lookalike);
+    }
+}
@@ -0,0 +1,183 @@
+use ethers::types::Address;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::blockchain::{BlockRange, Transaction};
+
+/// Transactions of one address kept between the stages of an import
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub address: Address,
+    /// Blocks the stored transactions were fetched from, `None` if unknown
+    #[serde(default)]
+    pub range: Option<BlockRange>,
+    pub transactions: Vec<Transaction>,
+}
+
+impl Snapshot {
+    pub fn new(address: Address) -> Self {
+        Self {
+            address,
+            range: None,
+            transactions: Vec::new(),
+        }
+    }
+
+    /// Return true unless the transactions were fetched from a later block on,
+    /// leaving out the balances the address held before.
+    pub fn has_full_history(&self) -> bool {
+        self.range.is_none_or(|range| range.start == 0)
+    }
+
+    /// Highest block number of the stored transactions
+    pub fn latest_block(&self) -> Option<u64> {
+        self.transactions.iter().map(|tx| tx.block_number).max()
+    }
+
+    /// Add transactions fetched later from `range`. Transactions already stored
+    /// are replaced, the result stays ordered by block and the stored range
+    /// grows to cover `range`.
+    pub fn merge(&mut self, range: BlockRange, txs: Vec<Transaction>) {
+        self.range = Some(match self.range {
+            Some(stored) => BlockRange {
+                start: stored.start.min(range.start),
+                end: stored.end.max(range.end),
+            },
+            None => range,
+        });
+        self.transactions
+            .retain(|stored| !txs.iter().any(|tx| tx.hash == stored.hash));
+        self.transactions.extend(txs);
+        self.transactions.sort_by_key(|tx| tx.block_number);
+    }
+}
+
+/// Directory holding the fetched transactions and their categorized copy as JSON
+#[derive(Debug)]
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn load(&self, name: &str) -> Result<Option<Snapshot>, Box<dyn Error>> {
+        let path = self.dir.join(name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_str(&fs::read_to_string(path)?)?))
+    }
+
+    fn save(&self, name: &str, snapshot: &Snapshot) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.dir.join(name), serde_json::to_string(snapshot)?)?;
+        Ok(())
+    }
+
+    /// Transactions as fetched from the chain, if any were stored.
+    pub fn fetched(&self) -> Result<Option<Snapshot>, Box<dyn Error>> {
+        self.load("transactions.json")
+    }
+
+    /// Store freshly fetched transactions. The categorized copy no longer
+    /// matches them and is removed.
+    pub fn save_fetched(&self, snapshot: &Snapshot) -> Result<(), Box<dyn Error>> {
+        self.save("transactions.json", snapshot)?;
+        let categorized = self.dir.join("categorized.json");
+        if categorized.exists() {
+            fs::remove_file(categorized)?;
+        }
+        Ok(())
+    }
+
+    /// Categorized transactions, falling back to the fetched ones when they
+    /// have not been categorized yet.
+    pub fn categorized(&self) -> Result<Snapshot, Box<dyn Error>> {
+        match self.load("categorized.json")? {
+            Some(snapshot) => Ok(snapshot),
+            None => self.fetched()?.ok_or_else(|| {
+                format!(
+                    "no transactions stored in {}, run `fetch` first",
+                    self.dir.display()
+                )
+                .into()
+            }),
+        }
+    }
+
+    pub fn save_categorized(&self, snapshot: &Snapshot) -> Result<(), Box<dyn Error>> {
+        self.save("categorized.json", snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::H256;
+    use std::env;
+
+    fn tx(hash: u8, block_number: u64) -> Transaction {
+        Transaction {
+            hash: H256::repeat_byte(hash),
+            block_number,
+            ..Default::default()
+        }
+    }
+
+    fn range(start: u64, end: u64) -> BlockRange {
+        BlockRange { start, end }
+    }
+
+    #[test]
+    fn merge_replaces_stored_transactions() {
+        let mut snapshot = Snapshot::new(Address::repeat_byte(0x11));
+        snapshot.merge(range(0, 20), vec![tx(1, 10), tx(2, 20)]);
+        let mut update = tx(2, 20);
+        update.category = Some("Trade".to_string());
+        snapshot.merge(range(15, 30), vec![tx(3, 15), update]);
+        let blocks: Vec<u64> = snapshot
+            .transactions
+            .iter()
+            .map(|t| t.block_number)
+            .collect();
+        assert_eq!(blocks, [10, 15, 20]);
+        assert_eq!(snapshot.transactions[2].category.as_deref(), Some("Trade"));
+        assert_eq!(snapshot.latest_block(), Some(20));
+        assert_eq!(snapshot.range, Some(range(0, 30)));
+        assert!(snapshot.has_full_history());
+
+        let mut partial = Snapshot::new(Address::repeat_byte(0x11));
+        partial.merge(range(15, 30), vec![tx(3, 15)]);
+        assert!(!partial.has_full_history());
+    }
+
+    #[test]
+    fn categorized_falls_back_to_fetched() {
+        let dir = env::temp_dir().join("store_test");
+        let _ = fs::remove_dir_all(&dir);
+        let store = Store::new(&dir);
+        assert!(store.categorized().is_err());
+
+        let mut snapshot = Snapshot::new(Address::repeat_byte(0x11));
+        snapshot.merge(BlockRange::default(), vec![tx(1, 10)]);
+        store.save_fetched(&snapshot).unwrap();
+        assert_eq!(store.categorized().unwrap().transactions.len(), 1);
+
+        snapshot.transactions.clear();
+        store.save_categorized(&snapshot).unwrap();
+        assert!(store.categorized().unwrap().transactions.is_empty());
+
+        // fetching again invalidates the categorized copy
+        snapshot.merge(BlockRange::default(), vec![tx(2, 20)]);
+        store.save_fetched(&snapshot).unwrap();
+        assert_eq!(store.categorized().unwrap().transactions.len(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,118 @@
+use ethers::types::Address;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+use crate::blockchain::{self, Transaction};
+
+/// Mapping from addresses to service or counterparty names
+#[derive(Debug, Default, Deserialize)]
+pub struct Tags(pub HashMap<Address, String>);
+
+impl Tags {
+    /// Load tags from the given file path. The format is inferred from the
+    /// extension and may be TOML, JSON, or YAML.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        blockchain::load_structured(path)
+    }
+
+    pub fn get(&self, addr: &Address) -> Option<&str> {
+        self.0.get(addr).map(String::as_str)
+    }
+}
+
+/// Return the address on the other side of a transfer between `from` and `to`
+/// from the point of view of `address`, if `address` takes part.
+fn other_side(address: Address, from: Address, to: Option<Address>) -> Option<Address> {
+    if from == address {
+        to
+    } else if to == Some(address) {
+        Some(from)
+    } else {
+        None
+    }
+}
+
+/// Fill [`Transaction::counterparty`] with the tag of the address on the other
+/// side of each transaction from the point of view of `address`, and the
+/// counterparty of every token transfer likewise. For direct calls to a token
+/// contract, such as `transfer`, the other side of the first transfer of the
+/// wallet is the counterparty rather than the contract.
+pub fn apply_tags(address: Address, txs: &mut [Transaction], tags: &Tags) {
+    for tx in txs {
+        for tr in &mut tx.transfers {
+            tr.counterparty = other_side(address, tr.from, tr.to)
+                .and_then(|a| tags.get(&a))
+                .map(str::to_string);
+        }
+        let token_call = tx
+            .transfers
+            .iter()
+            .any(|tr| Some(tr.token_contract) == tx.to);
+        let other = if token_call {
+            tx.transfers
+                .iter()
+                .find_map(|tr| other_side(address, tr.from, tr.to))
+        } else if tx.from == address {
+            tx.to
+        } else {
+            Some(tx.from)
+        };
+        if let Some(name) = other.and_then(|a| tags.get(&a)) {
+            tx.counterparty = Some(name.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Erc20Transfer;
+
+    #[test]
+    fn sample_tags_fill_counterparty() {
+        let tags = Tags::load(Path::new("../examples/tags.sample.toml")).unwrap();
+        let address = Address::repeat_byte(0x33);
+        let mut txs = vec![
+            Transaction {
+                from: address,
+                to: Some(Address::repeat_byte(0x11)),
+                ..Default::default()
+            },
+            Transaction {
+                from: Address::repeat_byte(0x22),
+                to: Some(address),
+                ..Default::default()
+            },
+            Transaction {
+                from: address,
+                to: Some(Address::repeat_byte(0x44)),
+                ..Default::default()
+            },
+        ];
+        // a token transfer to Alice called on the token contract
+        let token = Address::repeat_byte(0x55);
+        txs.push(Transaction {
+            from: address,
+            to: Some(token),
+            transfers: vec![Erc20Transfer {
+                token_contract: token,
+                from: address,
+                to: Some(Address::repeat_byte(0x11)),
+                value: 1u64.into(),
+                token_name: String::new(),
+                token_symbol: String::new(),
+                token_decimal: "0".to_string(),
+                counterparty: None,
+            }],
+            ..Default::default()
+        });
+        apply_tags(address, &mut txs, &tags);
+        assert_eq!(txs[0].counterparty.as_deref(), Some("Alice"));
+        assert_eq!(txs[1].counterparty.as_deref(), Some("Bob"));
+        assert_eq!(txs[2].counterparty, None);
+        assert_eq!(txs[3].counterparty.as_deref(), Some("Alice"));
+        assert_eq!(txs[3].transfers[0].counterparty.as_deref(), Some("Alice"));
+    }
+}
@@ -1,91 +1,191 @@
 use ethers::types::Address;
 use once_cell::sync::Lazy;
+use serde::Deserialize;
 use std::collections::HashMap;
 use std::str::FromStr;
 
-/// Mapping of known good token contract addresses to canonical symbols
-pub static GOOD_TOKENS: Lazy<HashMap<Address, &'static str>> = Lazy::new(|| {
+/// How a token is booked in the ledger
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    /// A plain asset held in the wallet
+    Asset,
+    /// A token representing debt owed, such as Aave debt tokens
+    Liability,
+    /// A receipt or derivative token representing a deposit elsewhere, such as
+    /// Aave aTokens
+    Receipt,
+}
+
+/// Registry entry for a known token
+#[derive(Clone, Copy, Debug)]
+pub struct TokenInfo {
+    /// Commodity ID, unique per contract
+    pub symbol: &'static str,
+    pub kind: TokenKind,
+    /// Asset shared with related contracts, such as USDC for USDC.e and aUSDC
+    pub group: Option<&'static str>,
+}
+
+impl TokenInfo {
+    const fn new(symbol: &'static str, kind: TokenKind) -> Self {
+        Self {
+            symbol,
+            kind,
+            group: None,
+        }
+    }
+
+    const fn grouped(symbol: &'static str, kind: TokenKind, group: &'static str) -> Self {
+        Self {
+            symbol,
+            kind,
+            group: Some(group),
+        }
+    }
+}
+
+/// Commodities booked as one by `--merge-commodities`, keyed by the commodity
+/// of the group. Set with `merge_groups` in the config file.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(transparent)]
+pub struct MergeGroups(HashMap<String, Vec<String>>);
+
+impl MergeGroups {
+    /// Return the groups of the whitelisted assets, such as USDC.e into USDC.
+    /// Receipt tokens stay apart so deposits are not mixed with liquid holdings.
+    pub fn builtin() -> Self {
+        let mut groups: HashMap<String, Vec<String>> = HashMap::new();
+        for info in GOOD_TOKENS.values().filter(|i| i.kind == TokenKind::Asset) {
+            if let Some(group) = info.group {
+                groups
+                    .entry(group.to_string())
+                    .or_default()
+                    .push(info.symbol.to_string());
+            }
+        }
+        Self(groups)
+    }
+
+    /// Return the commodity `symbol` is booked as, which is its group if it
+    /// belongs to one.
+    pub fn commodity<'a>(&'a self, symbol: &'a str) -> &'a str {
+        self.0
+            .iter()
+            .find(|(_, members)| members.iter().any(|m| m == symbol))
+            .map_or(symbol, |(group, _)| group.as_str())
+    }
+}
+
+/// Mapping of known good token contract addresses to their registry entries
+pub static GOOD_TOKENS: Lazy<HashMap<Address, TokenInfo>> = Lazy::new(|| {
     let mut m = HashMap::new();
     m.insert(
         Address::from_str("0xff970a61a04b1ca14834a43f5de4533ebddb5cc8").unwrap(),
-        "USDC",
+        TokenInfo::grouped("USDC.e", TokenKind::Asset, "USDC"),
     );
     m.insert(
         Address::from_str("0xfd086bc7cd5c481dcc9c85ebe478a1c0b69fcbb9").unwrap(),
-        "USDT",
+        TokenInfo::new("USDT", TokenKind::Asset),
     );
     m.insert(
         Address::from_str("0xda10009cbd5d07dd0cecc66161fc93d7c9000da1").unwrap(),
-        "DAI",
-    );
-    m.insert(
-        Address::from_str("0x2f2a2543b76a4166549f7aab2e75bef0aefc5b63").unwrap(),
-        "WBTC",
+        TokenInfo::new("DAI", TokenKind::Asset),
     );
     m.insert(
         Address::from_str("0x82af49447d8a07e3bd95bd0d56f35241523fbab1").unwrap(),
-        "WETH",
+        TokenInfo::new("WETH", TokenKind::Asset),
     );
     m.insert(
         Address::from_str("0xaf88d065e77c8cc2239327c5edb3a432268e5831").unwrap(),
-        "USDC",
+        TokenInfo::new("USDC", TokenKind::Asset),
     );
     m.insert(
         Address::from_str("0x724dc807b04555b71ed48a6896b6f41593b8c637").unwrap(),
-        "USDC",
+        TokenInfo::grouped("aUSDC", TokenKind::Receipt, "USDC"),
     );
     m.insert(
         Address::from_str("0x078f358208685046a11c85e8ad32895ded33a249").unwrap(),
-        "WBTC",
+        TokenInfo::grouped("aWBTC", TokenKind::Receipt, "WBTC"),
     );
     m.insert(
         Address::from_str("0x2f2a2543b76a4166549f7aab2e75bef0aefc5b0f").unwrap(),
-        "WBTC",
+        TokenInfo::new("WBTC", TokenKind::Asset),
     );
     m.insert(
         Address::from_str("0xf611aeb5013fd2c0511c9cd55c7dc5c1140741a6").unwrap(),
-        "Debt USDC",
+        TokenInfo::new("Debt USDC", TokenKind::Liability),
     );
     m.insert(
         Address::from_str("0x92b42c66840c7ad907b4bf74879ff3ef7c529473").unwrap(),
-        "Debt WBTC",
+        TokenInfo::new("Debt WBTC", TokenKind::Liability),
     );
     m.insert(
         Address::from_str("0x912ce59144191c1204e64559fe8253a0e49e6548").unwrap(),
-        "ARB",
+        TokenInfo::new("ARB", TokenKind::Asset),
     );
     m.insert(
         Address::from_str("0xe50fa9b3c56ffb159cb0fca61f5c9d750e8128c8").unwrap(),
-        "WETH",
+        TokenInfo::grouped("aWETH", TokenKind::Receipt, "WETH"),
     );
     m.insert(
         Address::from_str("0x6533afac2e7bccb20dca161449a13a32d391fb00").unwrap(),
-        "ARB",
+        TokenInfo::grouped("aARB", TokenKind::Receipt, "ARB"),
     );
     m.insert(
         Address::from_str("0x0c84331e39d6658cd6e6b9ba04736cc4c4734351").unwrap(),
-        "Debt WETH",
+        TokenInfo::new("Debt WETH", TokenKind::Liability),
     );
     m.insert(
         Address::from_str("0x953a573793604af8d41f306feb8274190db4ae0e").unwrap(),
-        "Debt LINK",
+        TokenInfo::new("Debt LINK", TokenKind::Liability),
     );
     m.insert(
         Address::from_str("0x18248226c16bf76c032817854e7c83a2113b4f06").unwrap(),
-        "Debt GHO",
+        TokenInfo::new("Debt GHO", TokenKind::Liability),
     );
     m.insert(
         Address::from_str("0x191c10aa4af7c30e871e70c95db0e4eb77237530").unwrap(),
-        "LINK",
+        TokenInfo::grouped("aLINK", TokenKind::Receipt, "LINK"),
     );
     m.insert(
         Address::from_str("0x44705f578135cc5d703b4c9c122528c73eb87145").unwrap(),
-        "Debt ARB",
+        TokenInfo::new("Debt ARB", TokenKind::Liability),
     );
     m
 });
 
-/// Return the canonical symbol for a token if it exists in the whitelist.
+/// Return the commodity ID of a token if it exists in the whitelist.
 pub fn get_symbol(addr: &Address) -> Option<&'static str> {
-    GOOD_TOKENS.get(addr).copied()
+    GOOD_TOKENS.get(addr).map(|info| info.symbol)
+}
+
+/// Return the classification of a whitelisted token.
+pub fn get_kind(addr: &Address) -> Option<TokenKind> {
+    GOOD_TOKENS.get(addr).map(|info| info.kind)
+}
+
+/// Return true if `symbol` is the commodity ID of a liability token.
+pub fn is_liability_symbol(symbol: &str) -> bool {
+    GOOD_TOKENS
+        .values()
+        .any(|info| info.symbol == symbol && info.kind == TokenKind::Liability)
+}
+
+/// Return every whitelisted contract booked as `commodity`, including the
+/// members of its group if `merge` groups are given.
+pub fn contracts_for(commodity: &str, merge: Option<&MergeGroups>) -> Vec<Address> {
+    GOOD_TOKENS
+        .iter()
+        .filter(|(_, info)| merge.map_or(info.symbol, |m| m.commodity(info.symbol)) == commodity)
+        .map(|(addr, _)| *addr)
+        .collect()
+}
+
+/// Return true if the token balance changes through interest accrual. Aave
+/// receipt and debt tokens grow without emitting Transfer events.
+pub fn is_rebasing(addr: &Address) -> bool {
+    matches!(
+        get_kind(addr),
+        Some(TokenKind::Receipt | TokenKind::Liability)
+    )
 }